@@ -0,0 +1,98 @@
+use std::fmt::Debug;
+use std::future::Future;
+
+use crate::manager::{Manager, ManagerFuture};
+
+type ConnectFn<C, E> = Box<dyn Fn() -> ManagerFuture<'static, Result<C, E>> + Send + Sync>;
+type ValidateFn<C, E> =
+    Box<dyn for<'a> Fn(&'a mut C) -> ManagerFuture<'a, Result<(), E>> + Send + Sync>;
+type IsClosedFn<C> = Box<dyn Fn(&mut C) -> bool + Send + Sync>;
+
+/// A `Manager` made of closures. Useful for prototypes and tests where a full `Manager` impl is too heavy.
+///
+/// Without `with_validate` every connection is treated as valid and without `with_is_closed` no connection is
+/// treated as closed.
+///
+/// # example:
+/// ```ignore
+/// use tang_rs::{Builder, ClosureManager};
+///
+/// let mgr = ClosureManager::new(|| async { TcpStream::connect("127.0.0.1:6379").await.map_err(MyError::from) })
+///     .with_validate(|conn| Box::pin(async move { ping(conn).await }))
+///     .with_is_closed(|conn| conn.peer_addr().is_err());
+///
+/// let pool = Builder::new().max_size(2).build(mgr).await?;
+/// ```
+pub struct ClosureManager<C, E> {
+    connect: ConnectFn<C, E>,
+    validate: Option<ValidateFn<C, E>>,
+    is_closed: Option<IsClosedFn<C>>,
+}
+
+impl<C, E> ClosureManager<C, E>
+where
+    C: Send + 'static,
+    E: Send + 'static + Debug + From<tokio::time::Elapsed>,
+{
+    /// Make a manager with an async closure establishing a new connection.
+    pub fn new<F, Fut>(connect: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<C, E>> + Send + 'static,
+    {
+        ClosureManager {
+            connect: Box::new(move || Box::pin(connect())),
+            validate: None,
+            is_closed: None,
+        }
+    }
+
+    /// Set the closure used as `Manager::is_valid`. The returned future borrows the connection so it has to be
+    /// boxed and pinned.(The same as the closure of `Pool::run`)
+    pub fn with_validate<F>(mut self, validate: F) -> Self
+    where
+        F: for<'a> Fn(&'a mut C) -> ManagerFuture<'a, Result<(), E>> + Send + Sync + 'static,
+    {
+        self.validate = Some(Box::new(validate));
+        self
+    }
+
+    /// Set the closure used as `Manager::is_closed`.
+    pub fn with_is_closed<F>(mut self, is_closed: F) -> Self
+    where
+        F: Fn(&mut C) -> bool + Send + Sync + 'static,
+    {
+        self.is_closed = Some(Box::new(is_closed));
+        self
+    }
+}
+
+impl<C, E> Manager for ClosureManager<C, E>
+where
+    C: Send + 'static,
+    E: Send + 'static + Debug + From<tokio::time::Elapsed>,
+{
+    type Connection = C;
+    type Error = E;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        (self.connect)()
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        match self.validate.as_ref() {
+            Some(validate) => validate(conn),
+            None => Box::pin(async { Ok(()) }),
+        }
+    }
+
+    fn is_closed(&self, conn: &mut Self::Connection) -> bool {
+        match self.is_closed.as_ref() {
+            Some(is_closed) => is_closed(conn),
+            None => false,
+        }
+    }
+}
@@ -0,0 +1,149 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures_util::stream::Stream;
+
+use crate::ConnectionId;
+
+// the max count of buffered events of a subscriber. The oldest events are dropped when a subscriber lags behind.
+const EVENT_BUFFER: usize = 128;
+
+/// The lifecycle events of connections. Subscribe to them with `Pool.events()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PoolEvent {
+    /// A new connection is established and added to pool.
+    Spawned { id: ConnectionId },
+    /// A connection is closed by the pool.
+    Reaped {
+        id: ConnectionId,
+        reason: ReapReason,
+    },
+    /// A connection is found broken and dropped.
+    Broken { id: ConnectionId },
+    /// A connection is returned to pool after use.
+    Returned { id: ConnectionId },
+    /// The subscriber is too slow and the count of events are missed.
+    Lagged { missed: u64 },
+}
+
+/// The reason of a `PoolEvent::Reaped`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReapReason {
+    IdleTimeout,
+    MaxLifetime,
+    Shrink,
+    /// The connection is returned after `Pool.shutdown` or still checked out when the `drain_timeout` elapses.
+    Shutdown,
+}
+
+#[derive(Default)]
+struct Subscriber {
+    buf: VecDeque<PoolEvent>,
+    missed: u64,
+    waker: Option<Waker>,
+    closed: bool,
+}
+
+// fan out the events to all the `PoolEvents` subscribers. Emitting never blocks on a subscriber.
+pub(crate) struct EventHub {
+    // checked before locking so emitting is cheap when nobody subscribes.
+    count: AtomicUsize,
+    subscribers: Mutex<Vec<Arc<Mutex<Subscriber>>>>,
+}
+
+impl EventHub {
+    pub(crate) fn new() -> Self {
+        EventHub {
+            count: AtomicUsize::new(0),
+            subscribers: Mutex::new(Vec::new()),
+        }
+    }
+
+    pub(crate) fn subscribe(&self) -> PoolEvents {
+        let subscriber = Arc::new(Mutex::new(Subscriber::default()));
+        let mut subscribers = self.subscribers.lock().unwrap();
+        subscribers.push(subscriber.clone());
+        self.count.store(subscribers.len(), Ordering::Release);
+        PoolEvents { subscriber }
+    }
+
+    pub(crate) fn emit(&self, event: PoolEvent) {
+        if self.count.load(Ordering::Acquire) == 0 {
+            return;
+        }
+
+        let mut wakers = Vec::new();
+        {
+            let mut subscribers = match self.subscribers.lock() {
+                Ok(subscribers) => subscribers,
+                Err(_) => return,
+            };
+            // the subscribers dropped by users only have the reference of us.
+            subscribers.retain(|subscriber| Arc::strong_count(subscriber) > 1);
+            self.count.store(subscribers.len(), Ordering::Release);
+
+            for subscriber in subscribers.iter() {
+                let mut subscriber = subscriber.lock().unwrap();
+                if subscriber.buf.len() == EVENT_BUFFER {
+                    subscriber.buf.pop_front();
+                    subscriber.missed += 1;
+                }
+                subscriber.buf.push_back(event);
+                if let Some(waker) = subscriber.waker.take() {
+                    wakers.push(waker);
+                }
+            }
+        }
+
+        for waker in wakers.into_iter() {
+            waker.wake();
+        }
+    }
+}
+
+impl Drop for EventHub {
+    fn drop(&mut self) {
+        if let Ok(subscribers) = self.subscribers.lock() {
+            for subscriber in subscribers.iter() {
+                let mut subscriber = subscriber.lock().unwrap();
+                subscriber.closed = true;
+                if let Some(waker) = subscriber.waker.take() {
+                    waker.wake();
+                }
+            }
+        }
+    }
+}
+
+/// A `Stream` of `PoolEvent` returned by `Pool.events()`.
+///
+/// The events are buffered for a slow subscriber up to a limit. After that the oldest events are dropped and a
+/// `PoolEvent::Lagged` is yielded with the count of missed events. The stream ends when the pool is dropped.
+pub struct PoolEvents {
+    subscriber: Arc<Mutex<Subscriber>>,
+}
+
+impl Stream for PoolEvents {
+    type Item = PoolEvent;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        let mut subscriber = self.subscriber.lock().unwrap();
+
+        if subscriber.missed != 0 {
+            let missed = std::mem::replace(&mut subscriber.missed, 0);
+            return Poll::Ready(Some(PoolEvent::Lagged { missed }));
+        }
+
+        match subscriber.buf.pop_front() {
+            Some(event) => Poll::Ready(Some(event)),
+            None if subscriber.closed => Poll::Ready(None),
+            None => {
+                subscriber.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
@@ -4,7 +4,6 @@
 //! [L3-37](https://github.com/OneSignal/L3-37/)
 //!
 //! # Known Limitation:
-//! No tests.
 //! no `tokio-0.1` support.
 //! can't be used in nested runtimes.
 //!
@@ -102,54 +101,122 @@
 //!}
 //!```
 
+use std::collections::hash_map::RandomState;
+use std::collections::HashMap;
+use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
+use std::hash::{BuildHasher, Hasher};
 use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
-use std::sync::Arc;
-use std::time::Instant;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
 
-use tokio::time::{interval, timeout, Timeout};
+use futures_util::future::{join_all, select_all};
+use tokio::time::{delay_for, interval, timeout, Elapsed, Timeout};
 
-pub use builder::Builder;
-pub use manager::Manager;
+pub use builder::{
+    Builder, ConfigError, PanicPolicy, ReaperMode, RecyclePolicy, RecyclingMethod, SpawnFuture,
+    WaitStrategy,
+};
+pub use closure_manager::ClosureManager;
+pub use events::{PoolEvent, PoolEvents, ReapReason};
+pub use manager::{ConnectionStats, Manager, SocketOptions, WithTls};
 #[cfg(feature = "mongodb")]
 pub use mongo_tang::{MongoManager, MongoPoolError};
+pub use multiplex::{MultiplexManager, MultiplexPool, StreamPermit};
+pub use pool_inner::{Pending, PoolStats, State, TimeoutInfo};
 #[cfg(feature = "tokio-postgres")]
 pub use postgres_tang::{CacheStatement, PostgresManager, PostgresPoolError, PrepareStatement};
 #[cfg(feature = "redis")]
 pub use redis_tang::{RedisManager, RedisPoolError};
+#[cfg(any(test, feature = "test-util"))]
+pub use test_manager::{TestConnection, TestError, TestManager};
 
+use crate::events::EventHub;
 use crate::manager::ManagerFuture;
-use crate::pool_inner::{PoolLock, State};
+use crate::pool_inner::{lock_recover, lock_with, PoolLock};
+use crate::util::atomic_duration::AtomicDuration;
+use crate::util::semaphore::Semaphore;
 
 mod builder;
+mod closure_manager;
+mod events;
 mod manager;
 #[cfg(feature = "mongodb")]
 mod mongo_tang;
+mod multiplex;
 mod pool_inner;
 #[cfg(feature = "tokio-postgres")]
 mod postgres_tang;
 #[cfg(feature = "redis")]
 mod redis_tang;
+#[cfg(any(test, feature = "test-util"))]
+mod test_manager;
+#[cfg(test)]
+mod tests;
 mod util;
 
+/// The id of a connection assigned by the pool. Ids are unique in the same pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+/// The id of a wait in the queue of pool passed to `Builder::on_wait_start` and `Builder::on_wait_end`. Ids are unique
+/// in the same pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WaitId(u64);
+
+impl WaitId {
+    pub fn get(self) -> u64 {
+        self.0
+    }
+}
+
+/// How a wait in the queue of pool ends.(See `Builder::on_wait_end`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WaitOutcome {
+    /// A connection is acquired.
+    Acquired,
+    /// The wait is dropped after the deadline of the acquire.
+    TimedOut,
+    /// The wait is dropped before the deadline.(e.g. the acquire future is dropped by the caller)
+    Cancelled,
+}
+
 pub struct Conn<M: Manager> {
     conn: M::Connection,
     birth: Instant,
+    id: ConnectionId,
+    // the `Pool.cache_generation` the caches of connection are built on.
+    cache_generation: u64,
 }
 
 pub struct IdleConn<M: Manager> {
     conn: Conn<M>,
     idle_start: Instant,
+    // the connection is not handed out before it when `reuse_cooldown` is set.
+    not_before: Option<Instant>,
 }
 
 impl<M: Manager> IdleConn<M> {
-    fn new(conn: M::Connection) -> Self {
+    fn new(conn: M::Connection, id: ConnectionId, cache_generation: u64) -> Self {
         let now = Instant::now();
         IdleConn {
-            conn: Conn { conn, birth: now },
+            conn: Conn {
+                conn,
+                birth: now,
+                id,
+                cache_generation,
+            },
             idle_start: now,
+            not_before: None,
         }
     }
 }
@@ -160,6 +227,7 @@ impl<M: Manager> From<Conn<M>> for IdleConn<M> {
         IdleConn {
             conn,
             idle_start: now,
+            not_before: None,
         }
     }
 }
@@ -169,27 +237,323 @@ impl<M: Manager> From<IdleConn<M>> for Conn<M> {
         Conn {
             conn: conn.conn.conn,
             birth: conn.conn.birth,
+            id: conn.conn.id,
+            cache_generation: conn.conn.cache_generation,
         }
     }
 }
 
 struct SharedPool<M: Manager + Send> {
     statics: Builder,
-    manager: M,
+    // shared with the blocking threads running `Manager::connect` with `Builder`'s spawn_blocking_connect setting.
+    // the manager of pool is `initial_manager` until `Pool.replace_manager` swaps in one in `replaced_manager`.
+    initial_manager: Arc<M>,
+    replaced_manager: RwLock<Option<Arc<M>>>,
+    // checked before locking `replaced_manager` so the uses of a never replaced manager don't touch the lock.
+    manager_replaced: AtomicBool,
+    // the count of reads and writes of `replaced_manager`.
+    #[cfg(test)]
+    manager_lock_uses: AtomicUsize,
     pool_lock: PoolLock<M>,
+    // false when `lazy_min_idle` is set and the pool is not used yet.
+    warmed: AtomicBool,
+    // true when the background tasks are started.
+    started: AtomicBool,
+    next_id: AtomicU64,
+    // the id of next wait when `on_wait_start` or `on_wait_end` is set.
+    next_wait_id: AtomicU64,
+    // max_size and min_idle could be lowered at runtime by `Pool.shrink_to`.
+    max_size: AtomicU8,
+    min_idle: AtomicU8,
+    // idle_timeout and max_lifetime could be changed at runtime by `Pool.set_idle_timeout` and
+    // `Pool.set_max_lifetime`.
+    idle_timeout: AtomicDuration,
+    max_lifetime: AtomicDuration,
+    // the count of connections opened by `Pool.get_ephemeral` and not dropped yet.
+    ephemeral: AtomicU8,
+    // the count of reapings in a row skipped as the pool was contended. Only the reaper touches it.
+    reap_skips: AtomicU8,
+    // bumped by `Pool.invalidate_caches`.
+    cache_generation: AtomicU64,
+    events: EventHub,
+    // the checkout time of connections held by `PoolRef`. Only tracked with `max_checkout_duration` so the acquires
+    // don't share a lock otherwise.
+    checkouts: Option<Mutex<HashMap<ConnectionId, Instant>>>,
+    // the count of connections held by `PoolRef` when the checkouts are not tracked. Used by `Pool.shutdown`.
+    checked_out: AtomicUsize,
+    // the count of connections forcibly closed by `Pool.shutdown` and not returned yet when the checkouts are not
+    // tracked.
+    reclaimed: AtomicUsize,
+    // set by `Pool.shutdown`. The returned connections are closed instead of being pushed back to pool.
+    closed: AtomicBool,
+    // limit the concurrent `Manager::connect` calls of all the spawns with `max_concurrent_connects`.
+    connect_limit: Option<Semaphore>,
+}
+
+// the manager of pool borrowed for one operation.
+enum ManagerRef<'a, M> {
+    Initial(&'a M),
+    Replaced(Arc<M>),
+}
+
+impl<M> Deref for ManagerRef<'_, M> {
+    type Target = M;
+
+    fn deref(&self) -> &M {
+        match self {
+            ManagerRef::Initial(manager) => manager,
+            ManagerRef::Replaced(manager) => manager,
+        }
+    }
+}
+
+// the start of an acquire. It's created when the acquire is first polled so the backtrace points at the caller of
+// `Pool.get()`.
+struct AcquireStart {
+    at: Instant,
+    #[cfg(feature = "capture-backtrace")]
+    backtrace: backtrace::Backtrace,
+}
+
+impl AcquireStart {
+    fn now() -> Self {
+        AcquireStart {
+            at: Instant::now(),
+            #[cfg(feature = "capture-backtrace")]
+            backtrace: backtrace::Backtrace::new_unresolved(),
+        }
+    }
+}
+
+// drop the pending connections when the establishing of them doesn't finish.
+struct PendingGuard<'a, M: Manager> {
+    pool_lock: &'a PoolLock<M>,
+    count: u8,
+}
+
+impl<M: Manager> Drop for PendingGuard<'_, M> {
+    fn drop(&mut self) {
+        if self.count != 0 {
+            self.pool_lock.decr_pending(self.count);
+        }
+    }
 }
 
 impl<M: Manager + Send> SharedPool<M> {
+    // the min_idle we try to maintain. We don't maintain any connection before the first use with `lazy_min_idle`
+    // and never maintain any with `spawn_on_acquire_only`.
+    fn min_idle(&self) -> u8 {
+        if self.statics.spawn_on_acquire_only {
+            0
+        } else if self.warmed.load(Ordering::Acquire) {
+            self.min_idle.load(Ordering::Relaxed)
+        } else {
+            0
+        }
+    }
+
+    // apply `connection_timeout_jitter` to the timeout of an acquire.
+    fn jitter(&self, timeout: Duration) -> Duration {
+        let jitter = self.statics.connection_timeout_jitter.max(0.0).min(1.0);
+        if jitter == 0.0 {
+            return timeout;
+        }
+
+        timeout.mul_f64(1.0 + jitter * (random() * 2.0 - 1.0))
+    }
+
+    fn max_size(&self) -> u8 {
+        self.max_size.load(Ordering::Relaxed)
+    }
+
+    fn cache_generation(&self) -> u64 {
+        self.cache_generation.load(Ordering::Acquire)
+    }
+
+    // assign an id to a new connection and call the on_connect_success callback.
+    fn on_connect(&self) -> ConnectionId {
+        let id = ConnectionId(self.next_id.fetch_add(1, Ordering::Relaxed));
+        if let Some(f) = self.statics.on_connect_success.as_ref() {
+            f(id);
+        }
+        self.events.emit(PoolEvent::Spawned { id });
+        id
+    }
+
+    // add the pending connections of the warmup to min_idle. The pending ones already in flight(e.g. spawned by the
+    // reaper) are counted so we don't over spawn.
+    fn warmup_pending(&self) -> u8 {
+        self.pool_lock.incr_pending_to(self.min_idle()).unwrap_or(0)
+    }
+
+    // start a wait of the queue if any of the wait hooks is set.
+    fn on_wait_start(&self) -> Option<(WaitId, Instant)> {
+        let statics = &self.statics;
+        if statics.on_wait_start.is_none() && statics.on_wait_end.is_none() {
+            return None;
+        }
+
+        let id = WaitId(self.next_wait_id.fetch_add(1, Ordering::Relaxed));
+        if let Some(f) = statics.on_wait_start.as_ref() {
+            f(id);
+        }
+        Some((id, Instant::now()))
+    }
+
+    fn on_wait_end(&self, (id, start): (WaitId, Instant), outcome: WaitOutcome) {
+        if let Some(f) = self.statics.on_wait_end.as_ref() {
+            f(id, start.elapsed(), outcome);
+        }
+    }
+
+    // call the on_disconnect callback when a connection is removed from pool.
+    fn on_disconnect(&self, id: ConnectionId) {
+        if let Some(f) = self.statics.on_disconnect.as_ref() {
+            f(id);
+        }
+    }
+
+    // return false if we have hit the max_ephemeral.
+    fn incr_ephemeral(&self) -> bool {
+        let max = self.statics.max_ephemeral;
+        let mut current = self.ephemeral.load(Ordering::Relaxed);
+        loop {
+            if current >= max {
+                return false;
+            }
+            match self.ephemeral.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return true,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    fn decr_ephemeral(&self) {
+        self.ephemeral.fetch_sub(1, Ordering::AcqRel);
+    }
+
+    // a connection is dropped as the pool is over max_size.
+    fn on_shrink(&self, id: ConnectionId) {
+        self.events.emit(PoolEvent::Reaped {
+            id,
+            reason: ReapReason::Shrink,
+        });
+        self.on_disconnect(id);
+    }
+
+    // move the connections in the thread local cache back to pool.
+    fn flush_local(&self) {
+        for conn in self.pool_lock.flush_local(self.max_size()).into_iter() {
+            self.on_shrink(conn.conn.id);
+        }
+    }
+
+    // a connection is found broken and removed from pool.
+    fn on_broken(&self, id: ConnectionId) {
+        self.events.emit(PoolEvent::Broken { id });
+        self.on_disconnect(id);
+    }
+
+    // record the checkout of a connection for `max_checkout_duration` and `Pool.shutdown`.
+    fn check_out(&self, id: ConnectionId) {
+        match self.checkouts.as_ref() {
+            Some(checkouts) => {
+                lock_with(checkouts, self.statics.panic_policy).insert(id, Instant::now());
+            }
+            None => {
+                self.checked_out.fetch_add(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    // return false if the connection is already reclaimed(by `max_checkout_duration` or `Pool.shutdown`) and no
+    // longer a part of pool.
+    fn check_in(&self, id: ConnectionId) -> bool {
+        if let Some(checkouts) = self.checkouts.as_ref() {
+            return lock_recover(checkouts).remove(&id).is_some();
+        }
+
+        // without tracking we don't know which connections are reclaimed by `Pool.shutdown`. Any of the returned
+        // connections can take a reclaimed slot as they are all closed after the shutdown anyway.
+        let mut reclaimed = self.reclaimed.load(Ordering::Acquire);
+        while reclaimed != 0 {
+            match self.reclaimed.compare_exchange_weak(
+                reclaimed,
+                reclaimed - 1,
+                Ordering::AcqRel,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    self.on_close(id);
+                    return false;
+                }
+                Err(current) => reclaimed = current,
+            }
+        }
+        self.checked_out.fetch_sub(1, Ordering::AcqRel);
+        true
+    }
+
+    // reclaim all the connections still checked out and close them. Return the count of them.
+    // The untracked connections are reported when they are returned as we don't know their ids.
+    fn force_close(&self) -> u8 {
+        let ids = match self.checkouts.as_ref() {
+            Some(checkouts) => lock_recover(checkouts)
+                .drain()
+                .map(|(id, _)| id)
+                .collect::<Vec<_>>(),
+            None => {
+                let count = self.checked_out.swap(0, Ordering::AcqRel);
+                self.reclaimed.fetch_add(count, Ordering::AcqRel);
+                for _ in 0..count {
+                    self.pool_lock.decr_spawned(0);
+                }
+                return count.min(u8::MAX as usize) as u8;
+            }
+        };
+        for id in ids.iter() {
+            self.pool_lock.decr_spawned(0);
+            self.on_close(*id);
+        }
+        ids.len() as u8
+    }
+
+    // a connection is closed by `Pool.shutdown`.
+    fn on_close(&self, id: ConnectionId) {
+        self.events.emit(PoolEvent::Reaped {
+            id,
+            reason: ReapReason::Shutdown,
+        });
+        self.on_disconnect(id);
+    }
+
+    // remove the connections held longer than max_checkout_duration from checkouts and return their ids.
+    fn take_overdue(&self, max_checkout_duration: Duration) -> Vec<ConnectionId> {
+        let now = Instant::now();
+        let mut checkouts = match self.checkouts.as_ref() {
+            Some(checkouts) => lock_recover(checkouts),
+            None => return Vec::new(),
+        };
+        let overdue = checkouts
+            .iter()
+            .filter(|(_, checkout)| now.duration_since(**checkout) > max_checkout_duration)
+            .map(|(id, _)| *id)
+            .collect::<Vec<_>>();
+        for id in overdue.iter() {
+            checkouts.remove(id);
+        }
+        overdue
+    }
+
     async fn drop_conn(&self) -> Result<(), M::Error> {
         //  We might need to spin up more connections to maintain the idle limit, e.g.
         //  if we hit connection lifetime limits
-        let pending_count = self.pool_lock.decr_spawned(|total_now| {
-            if total_now < self.statics.min_idle {
-                Some(self.statics.min_idle - total_now)
-            } else {
-                None
-            }
-        });
+        let pending_count = self.pool_lock.decr_spawned(self.min_idle());
 
         match pending_count {
             Some(count) => self.replenish_idle_conn(count).await,
@@ -198,61 +562,326 @@ impl<M: Manager + Send> SharedPool<M> {
     }
 
     // use `Builder`'s connection_timeout setting to cancel the `connect` method and return error.
+    // then run `Builder`'s connection_init_sql statements on the new connection.
+    async fn connect(&self) -> Result<M::Connection, M::Error> {
+        #[cfg(feature = "metrics")]
+        let start = Instant::now();
+
+        let mut conn = {
+            // the permit of `max_concurrent_connects` is only held while the manager connects.
+            let _permit = match self.connect_limit.as_ref() {
+                Some(connect_limit) => Some(connect_limit.acquire().await),
+                None => None,
+            };
+            self.connect_manager()
+                .timeout(self.connect_timeout())
+                .await??
+        };
+
+        let manager = self.manager();
+        for sql in self.statics.connection_init_sql.iter() {
+            manager
+                .execute(&mut conn, sql)
+                .timeout(self.statics.connection_timeout)
+                .await??;
+        }
+
+        #[cfg(feature = "metrics")]
+        self.pool_lock.record_connect_time(start.elapsed());
+
+        Ok(conn)
+    }
+
+    // the timeout of connect. It's `Builder`'s create_timeout setting when the pool is cold and it's set.
+    fn connect_timeout(&self) -> Duration {
+        match self.statics.create_timeout {
+            Some(create_timeout) if self.pool_lock.stats().connections == 0 => create_timeout,
+            _ => self.statics.connection_timeout,
+        }
+    }
+
+    // run `Manager::connect` on a blocking thread with `Builder`'s spawn_blocking_connect setting.
+    fn connect_manager(&self) -> ManagerFuture<Result<M::Connection, M::Error>> {
+        let manager = self.manager_arc();
+
+        #[cfg(feature = "blocking")]
+        {
+            if self.statics.spawn_blocking_connect {
+                return Box::pin(async move {
+                    tokio::task::spawn_blocking(move || util::block_on::block_on(manager.connect()))
+                        .await
+                        .unwrap_or_else(|e| {
+                            panic!("Manager::connect panicked on a blocking thread: {:?}", e)
+                        })
+                });
+            }
+        }
+
+        Box::pin(async move { manager.connect().await })
+    }
+
+    // the manager is swapped by `Pool.replace_manager`. It's only cloned out of the lock after a replacement.
+    fn manager(&self) -> ManagerRef<'_, M> {
+        if self.manager_replaced.load(Ordering::Acquire) {
+            ManagerRef::Replaced(self.manager_arc())
+        } else {
+            ManagerRef::Initial(&self.initial_manager)
+        }
+    }
+
+    // an owned manager for the futures outliving the borrow of pool. The same as `manager` the lock is only read
+    // after a replacement.
+    fn manager_arc(&self) -> Arc<M> {
+        if !self.manager_replaced.load(Ordering::Acquire) {
+            return self.initial_manager.clone();
+        }
+
+        #[cfg(test)]
+        self.manager_lock_uses.fetch_add(1, Ordering::Relaxed);
+
+        // the lock only guards the swap of an `Arc` so a poisoned one still holds a valid manager.
+        match self
+            .replaced_manager
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+        {
+            Some(manager) => manager.clone(),
+            None => self.initial_manager.clone(),
+        }
+    }
+
+    // a failed connect is retried at most `Builder`'s retry_connect times before we give up.
     async fn add_idle_conn(&self) -> Result<(), M::Error> {
-        let conn = self
-            .manager
-            .connect()
-            .timeout(self.statics.connection_timeout)
-            .await
-            .map_err(|e| {
-                self.pool_lock.decr_pending(1);
-                e
-            })?
-            .map_err(|e| {
-                self.pool_lock.decr_pending(1);
-                e
-            })?;
+        // the pending is dropped by the guard if we return early, `Manager::connect` panics or this future is dropped.
+        let mut guard = PendingGuard {
+            pool_lock: &self.pool_lock,
+            count: 1,
+        };
+
+        let mut retry = 0;
+        let conn = loop {
+            let e = match self.connect().await {
+                Ok(conn) => break conn,
+                Err(e) => e,
+            };
+
+            if retry >= self.statics.retry_connect {
+                return Err(e);
+            }
+            retry += 1;
+        };
 
-        self.pool_lock.put_back_incr_spawned(IdleConn::new(conn));
+        // a panic of the on_connect_success callback drops the connection and the pending is dropped by the guard.
+        let id = self.on_connect();
+        let rejected = self.pool_lock.put_back_incr_spawned(
+            IdleConn::new(conn, id, self.cache_generation()),
+            self.max_size(),
+        );
+        // the pending is dropped in put_back_incr_spawned.
+        guard.count = 0;
+
+        if let Some(conn) = rejected {
+            self.on_disconnect(conn.conn.id);
+        }
 
         Ok(())
     }
 
+    // put back a connection to pool and sum up it's activity.
+    // The connection is dropped if the pool is over max_size.(After `Pool.shrink_to`)
+    fn put_back(&self, mut conn: Conn<M>) {
+        let stats = self.manager().stats(&mut conn.conn);
+        let id = conn.id;
+        let mut conn: IdleConn<M> = conn.into();
+        if self.closed.load(Ordering::Acquire) {
+            self.pool_lock.decr_spawned(0);
+            self.on_close(id);
+            return;
+        }
+        if let Some(cooldown) = self.statics.reuse_cooldown {
+            conn.not_before = Some(conn.idle_start + cooldown);
+        }
+        match self.pool_lock.put_back(conn, stats, self.max_size()) {
+            Some(conn) => self.on_shrink(conn.conn.id),
+            None => self.events.emit(PoolEvent::Returned { id }),
+        }
+    }
+
+    // convert a timeout of acquire into M::Error with the detail of pool at the time.
+    fn timeout_error(&self, e: Elapsed, start: &AcquireStart) -> M::Error {
+        let info = TimeoutInfo {
+            waited: start.at.elapsed(),
+            stats: self.pool_lock.stats(),
+            #[cfg(feature = "capture-backtrace")]
+            backtrace: start.backtrace.clone(),
+        };
+        self.manager().timeout_error(e, info)
+    }
+
+    // check the connection with `Builder`'s validation_query if it's set. Otherwise use `Manager::is_valid`.
+    // The outer error is the timeout of the check and the inner one is the error of a broken connection.
     async fn check_conn(&self, conn: &mut Conn<M>) -> Result<Result<(), M::Error>, M::Error> {
-        self.manager
-            .is_valid(&mut conn.conn)
-            .timeout(self.statics.connection_timeout)
-            .await?
-            .map(Ok)
+        let manager = self.manager();
+        let check = match self.statics.validation_query.as_ref() {
+            Some(query) => manager.execute(&mut conn.conn, query),
+            None => manager.is_valid(&mut conn.conn),
+        };
+        Ok(check.timeout(self.statics.connection_timeout).await?)
     }
 
+    // stop on the first error. The pending connections not started yet are dropped.
     async fn replenish_idle_conn(&self, pending_count: u8) -> Result<(), M::Error> {
-        for i in 0..pending_count {
-            self.add_idle_conn().await.map_err(|e| {
-                // we return when an error occur so we should drop all the pending after the i.
-                // (the pending of i is already dropped in add_connection method)
-                self.pool_lock.decr_pending(pending_count - i - 1);
-                e
-            })?;
+        let errors = self.add_idle_conns(pending_count, true).await;
+        match errors.into_iter().next() {
+            Some(e) => Err(e),
+            None => Ok(()),
         }
-        Ok(())
     }
 
-    async fn reap_idle_conn(&self) -> Result<(), M::Error> {
-        let now = Instant::now();
+    // like `replenish_idle_conn` but we don't stop on the first error. All the errors are collected and returned.
+    async fn replenish_idle_conn_partial(&self, pending_count: u8) -> Vec<M::Error> {
+        self.add_idle_conns(pending_count, false).await
+    }
+
+    // at most `warmup_concurrency` connections are established at the same time and a finished one starts the next
+    // right away.
+    async fn add_idle_conns(&self, pending_count: u8, stop_on_error: bool) -> Vec<M::Error> {
+        let limit = Semaphore::new(self.statics.warmup_concurrency.max(1) as usize);
+        let failed = AtomicBool::new(false);
+
+        let results = join_all((0..pending_count).map(|_| async {
+            // the guard drops the pending when we return before the connect starts, panic or this future is dropped.
+            // (the pending of a started one is dropped in add_idle_conn method)
+            let mut guard = PendingGuard {
+                pool_lock: &self.pool_lock,
+                count: 1,
+            };
+            let _permit = limit.acquire().await;
+            if stop_on_error && failed.load(Ordering::Acquire) {
+                return Ok(());
+            }
+            guard.count = 0;
 
-        let pending_new = self
+            let result = self.add_idle_conn().await;
+            if result.is_err() {
+                failed.store(true, Ordering::Release);
+            }
+            result
+        }))
+        .await;
+
+        results.into_iter().filter_map(Result::err).collect()
+    }
+
+    fn on_expire(&self, id: ConnectionId) {
+        self.events.emit(PoolEvent::Reaped {
+            id,
+            reason: ReapReason::MaxLifetime,
+        });
+        self.on_disconnect(id);
+    }
+
+    // return true if the connection has lived past max_lifetime. The lifetime is counted from the connect.
+    fn lifetime_passed(&self, conn: &Conn<M>, now: Instant) -> bool {
+        self.max_lifetime
+            .load()
+            .map(|lifetime| now >= conn.birth + lifetime)
+            .unwrap_or(false)
+    }
+
+    // check a batch of idle connections with `Builder`'s health_check_interval setting. The healthy ones are pushed
+    // back and the broken ones are dropped and replaced to maintain min_idle.
+    async fn health_check_idle(&self) {
+        let conns = self
             .pool_lock
-            .try_drop_conns(self.statics.min_idle, |conn| {
-                let mut should_drop = false;
-                if let Some(timeout) = self.statics.idle_timeout {
-                    should_drop |= now >= conn.idle_start + timeout;
+            .take_idle_for_check(self.statics.health_check_batch.max(1));
+
+        for mut conn in conns.into_iter() {
+            match self.check_conn(&mut conn.conn).await {
+                Ok(Ok(())) => {
+                    // the idle_start is kept so the checked connection can still be reaped by idle_timeout.
+                    let stats = ConnectionStats::default();
+                    if let Some(conn) = self.pool_lock.put_back(conn, stats, self.max_size()) {
+                        self.on_shrink(conn.conn.id);
+                    }
                 }
-                if let Some(lifetime) = self.statics.max_lifetime {
-                    should_drop |= now >= conn.idle_start + lifetime;
+                _ => {
+                    self.on_broken(conn.conn.id);
+                    drop(conn);
+                    let _ = self.drop_conn().await;
                 }
-                should_drop
-            });
+            }
+        }
+    }
+
+    async fn reap_idle_conn(&self) -> Result<(), M::Error> {
+        // the cached connections are idle too.
+        self.flush_local();
+
+        let now = Instant::now();
+        let idle_timeout = self.idle_timeout.load();
+        let max_lifetime = self.max_lifetime.load();
+
+        let mut dropped = Vec::new();
+
+        let min_idle = self.min_idle();
+        let max_drop = self.statics.max_idle_per_reap;
+        let should_drop = |conn: &IdleConn<M>| {
+            if now < conn.idle_start + self.statics.min_idle_age {
+                return false;
+            }
+            let mut should_drop = false;
+            if let Some(timeout) = idle_timeout {
+                should_drop |= now >= conn.idle_start + timeout;
+            }
+            if let Some(lifetime) = max_lifetime {
+                should_drop |= now >= conn.conn.birth + lifetime;
+            }
+            should_drop
+        };
+
+        let skips = self.reap_skips.load(Ordering::Relaxed);
+        let blocking = match self.statics.reaper_mode {
+            ReaperMode::BestEffort => false,
+            ReaperMode::ForceAfter { skips: max_skips } => skips >= max_skips,
+        };
+
+        let pending_new = if blocking {
+            Ok(self
+                .pool_lock
+                .drop_conns_blocking(min_idle, max_drop, &mut dropped, should_drop))
+        } else {
+            self.pool_lock
+                .try_drop_conns(min_idle, max_drop, &mut dropped, should_drop)
+        };
+
+        let pending_new = match pending_new {
+            Ok(pending_new) => {
+                self.reap_skips.store(0, Ordering::Relaxed);
+                pending_new
+            }
+            Err(()) => {
+                self.reap_skips
+                    .store(skips.saturating_add(1), Ordering::Relaxed);
+                None
+            }
+        };
+
+        for conn in dropped.into_iter() {
+            let lifetime_passed = max_lifetime
+                .map(|lifetime| now >= conn.conn.birth + lifetime)
+                .unwrap_or(false);
+            if lifetime_passed {
+                self.on_expire(conn.conn.id);
+            } else {
+                self.events.emit(PoolEvent::Reaped {
+                    id: conn.conn.id,
+                    reason: ReapReason::IdleTimeout,
+                });
+                self.on_disconnect(conn.conn.id);
+            }
+        }
 
         match pending_new {
             Some(pending_new) => self.replenish_idle_conn(pending_new).await,
@@ -266,12 +895,20 @@ impl<M: Manager + Send> SharedPool<M> {
     }
 
     // ToDo: we should figure a way to handle failed spawn.
+    // use `Builder`'s spawner if there is one. Otherwise we spawn on tokio runtime.
     fn spawn<F>(&self, f: F)
     where
         F: Future + Send + 'static,
         F::Output: Send + 'static,
     {
-        tokio::spawn(f);
+        match self.statics.spawner.as_ref() {
+            Some(spawner) => spawner(Box::pin(async move {
+                let _ = f.await;
+            })),
+            None => {
+                tokio::spawn(f);
+            }
+        }
     }
 }
 
@@ -290,13 +927,50 @@ impl<M: Manager + Send> fmt::Debug for Pool<M> {
 }
 
 impl<M: Manager + Send> Pool<M> {
-    fn new(builder: Builder, manager: M) -> Self {
-        let size = builder.max_size as usize;
+    fn new(builder: Builder, mut manager: M) -> Self {
+        if let Some(options) = builder.socket_options.as_ref() {
+            manager.apply_socket_options(options);
+        }
+
+        let warmed = !builder.lazy_min_idle;
+        let max_size = AtomicU8::new(builder.max_size);
+        let min_idle = match builder.adaptive_min_idle {
+            Some((floor, _)) => AtomicU8::new(floor),
+            None => AtomicU8::new(builder.min_idle),
+        };
+        let idle_timeout = AtomicDuration::new(builder.idle_timeout);
+        let max_lifetime = AtomicDuration::new(builder.max_lifetime);
+        let pool_lock = PoolLock::new(&builder);
+        let connect_limit = builder.max_concurrent_connects.map(Semaphore::new);
+        let checkouts = builder
+            .max_checkout_duration
+            .map(|_| Mutex::new(HashMap::new()));
 
         Pool(Arc::new(SharedPool {
             statics: builder,
-            manager,
-            pool_lock: PoolLock::new(size),
+            initial_manager: Arc::new(manager),
+            replaced_manager: RwLock::new(None),
+            manager_replaced: AtomicBool::new(false),
+            #[cfg(test)]
+            manager_lock_uses: AtomicUsize::new(0),
+            pool_lock,
+            warmed: AtomicBool::new(warmed),
+            started: AtomicBool::new(false),
+            next_id: AtomicU64::new(0),
+            next_wait_id: AtomicU64::new(0),
+            max_size,
+            min_idle,
+            idle_timeout,
+            max_lifetime,
+            ephemeral: AtomicU8::new(0),
+            reap_skips: AtomicU8::new(0),
+            cache_generation: AtomicU64::new(0),
+            events: EventHub::new(),
+            checkouts,
+            checked_out: AtomicUsize::new(0),
+            reclaimed: AtomicUsize::new(0),
+            closed: AtomicBool::new(false),
+            connect_limit,
         }))
     }
 
@@ -333,96 +1007,684 @@ impl<M: Manager + Send> Pool<M> {
     pub async fn init(&self) -> Result<(), M::Error> {
         let shared_pool = &self.0;
 
-        schedule_reaping(shared_pool);
-        garbage_collect(shared_pool);
+        if !shared_pool.statics.start_background_tasks {
+            return Ok(());
+        }
+        start_background_tasks(shared_pool);
 
         shared_pool
-            .replenish_idle_conn(shared_pool.statics.min_idle)
+            .replenish_idle_conn(shared_pool.warmup_pending())
             .await?;
 
         Ok(())
     }
 
-    /// Return a reference of `Arc<SharedPool<Manager>>` and a `Option<Manager::Connection>`.
-    /// The `PoolRef` should be drop asap when you finish the use of it.
-    pub async fn get(&self) -> Result<PoolRef<'_, M>, M::Error> {
-        let conn = self.get_conn(0).await?;
-
-        Ok(PoolRef {
-            conn: Some(conn),
-            pool: &self.0,
-        })
-    }
+    /// manually initialize pool like `Pool.init` but the warmup is bounded by `Builder`'s `warmup_timeout` setting.
+    /// On timeout the missing connections are replenished in background.(Or an error returns with
+    /// `warmup_timeout_error`)
+    pub async fn init_warm(&self) -> Result<(), M::Error> {
+        let shared_pool = &self.0;
 
-    /// Run the pool with a closure.
-    /// Usually slightly faster than `Pool.get()` as we only do conditional broken check according to the closure result.
-    pub async fn run<T, E, F>(&self, f: F) -> Result<T, E>
-    where
-        F: FnOnce(&mut M::Connection) -> Pin<Box<dyn Future<Output = Result<T, E>> + Send + '_>>,
-        E: From<M::Error>,
-        T: Send + 'static,
-    {
-        let mut conn = self.get_conn(0).await?;
+        if !shared_pool.statics.start_background_tasks {
+            return Ok(());
+        }
+        start_background_tasks(shared_pool);
 
-        let result = f(&mut conn.conn).await;
+        let min_idle = shared_pool.min_idle();
+        let warmup = shared_pool.replenish_idle_conn(shared_pool.warmup_pending());
 
-        // only check if the connection is broken when we get an error from result
-        let broken = if result.is_err() {
-            self.0.manager.is_closed(&mut conn.conn)
-        } else {
-            false
+        let timeout = match shared_pool.statics.warmup_timeout {
+            Some(timeout) => timeout,
+            None => return warmup.await,
         };
 
-        if broken {
-            spawn_drop(&self.0);
-        } else {
-            self.0.pool_lock.put_back(conn.into())
+        match warmup.timeout(timeout).await {
+            Ok(result) => result,
+            Err(e) if shared_pool.statics.warmup_timeout_error => Err(e.into()),
+            Err(_) => {
+                // the pending connections not established yet are dropped along with the warmup future.
+                if let Some(pending_count) = shared_pool.pool_lock.incr_pending_to(min_idle) {
+                    spawn_replenish(shared_pool, pending_count);
+                }
+                Ok(())
+            }
         }
-        result
     }
 
-    // Recursive when the connection is broken(When enabling the always_check). We exit with at most 3 retries and return an error.
-    fn get_conn(&self, mut retry: u8) -> ManagerFuture<Result<Conn<M>, M::Error>> {
-        Box::pin(async move {
-            let shared_pool = &self.0;
+    /// manually initialize pool like `Pool.init`. The difference is a failed connection doesn't abort the initialization.
+    /// All the errors of failed connections are returned and the missing connections will be replenished in background.
+    pub async fn init_partial(&self) -> Result<(), Vec<M::Error>> {
+        let shared_pool = &self.0;
+
+        if !shared_pool.statics.start_background_tasks {
+            return Ok(());
+        }
+        start_background_tasks(shared_pool);
+
+        let errors = shared_pool
+            .replenish_idle_conn_partial(shared_pool.warmup_pending())
+            .await;
 
-            let mut conn = shared_pool
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            // the connections already restored by the background tasks are counted so we don't over spawn.
+            if let Some(pending_count) = shared_pool
                 .pool_lock
-                .lock(shared_pool)
-                .timeout(shared_pool.statics.wait_timeout)
-                .await?
-                .into();
+                .incr_pending_to(shared_pool.min_idle())
+            {
+                spawn_replenish(shared_pool, pending_count);
+            }
+            Err(errors)
+        }
+    }
 
-            if shared_pool.statics.always_check {
-                let result = shared_pool.check_conn(&mut conn).await.map_err(|e| {
-                    spawn_drop(shared_pool);
-                    e
-                })?;
+    /// Acquire a connection from each of the pools at the same time and return the first one obtained along with the
+    /// index of its pool. The other acquires are canceled.
+    ///
+    /// Useful for picking the fastest pool of multiple regions. An error is returned only if all the acquires fail, and
+    /// it's the error of the last failed one.
+    ///
+    /// # Panics
+    /// Panics if `pools` is empty.
+    pub async fn race<'a>(pools: &[&'a Pool<M>]) -> Result<(usize, PoolRef<'a, M>), M::Error> {
+        assert!(!pools.is_empty(), "Pool::race needs at least one pool");
 
-                if let Err(e) = result {
-                    spawn_drop(shared_pool);
-                    if retry == 3 {
-                        return Err(e);
-                    } else {
-                        retry += 1;
-                        return self.get_conn(retry).await;
-                    }
-                }
-            };
+        let mut futs = pools
+            .iter()
+            .enumerate()
+            .map(|(index, pool)| Box::pin(async move { (index, pool.get().await) }))
+            .collect::<Vec<_>>();
 
-            Ok(conn)
-        })
+        loop {
+            let ((index, result), _, rest) = select_all(futs).await;
+            match result {
+                Ok(conn) => return Ok((index, conn)),
+                Err(e) if rest.is_empty() => return Err(e),
+                Err(_) => futs = rest,
+            }
+        }
     }
 
-    /// Return a state of the pool inner. This call will block the thread and wait for lock.
+    /// Return a reference of `Arc<SharedPool<Manager>>` and a `Option<Manager::Connection>`.
+    /// The `PoolRef` should be drop asap when you finish the use of it.
+    ///
+    /// With `tracing` feature a `pool.acquire` span is created as a child of the current span and it stays open until
+    /// the `PoolRef` is dropped. So the span covers both the acquire and the time the connection is held.
+    pub async fn get(&self) -> Result<PoolRef<'_, M>, M::Error> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("pool.acquire");
+
+        let conn = self.acquire().await?;
+
+        Ok(PoolRef {
+            conn: Some(conn),
+            pool: &self.0,
+            ephemeral: false,
+            reclaimed: false,
+            #[cfg(feature = "blocking")]
+            blocking: false,
+            #[cfg(feature = "tracing")]
+            span,
+        })
+    }
+
+    /// Return a `PoolRef` like `Pool.get()` but for non-async context. The current thread is blocked until we get a
+    /// connection or `connection_timeout` passed.
+    ///
+    /// The acquire is driven on the current thread by a minimal executor so the caller doesn't need to run a runtime.
+    /// The timers and the background tasks of it run on a runtime owned by this crate. It's started on the first call
+    /// and shared by all pools. (The tasks still go to `Builder::spawner` if there is one)
+    ///
+    /// The returned `PoolRef` can be dropped in non-async context too. This function should not be called inside an
+    /// async context as it blocks the worker of runtime.
+    ///
+    /// # Panics:
+    /// This function panics when the runtime of this crate can't be started.
+    #[cfg(feature = "blocking")]
+    pub fn get_blocking(&self) -> Result<PoolRef<'_, M>, M::Error> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("pool.acquire");
+
+        let conn = util::block_on::runtime().enter(|| util::block_on::block_on(self.acquire()))?;
+
+        Ok(PoolRef {
+            conn: Some(conn),
+            pool: &self.0,
+            ephemeral: false,
+            reclaimed: false,
+            #[cfg(feature = "blocking")]
+            blocking: true,
+            #[cfg(feature = "tracing")]
+            span,
+        })
+    }
+
+    /// Return a `PoolRef` like `Pool.get()` but open a one-off connection outside the pool as the last resort when no
+    /// connection can be acquired from the saturated pool within `connection_timeout`.
+    ///
+    /// The ephemeral connection is not counted against `max_size` and is closed when the `PoolRef` is dropped instead
+    /// of being pushed back to pool. The count of simultaneous ephemeral connections is capped by `max_ephemeral` and
+    /// the timeout error is returned when the cap is hit.
+    ///
+    /// This is a deliberate escape hatch for critical requests. A pool that regularly needs it should be enlarged.
+    pub async fn get_ephemeral(&self) -> Result<PoolRef<'_, M>, M::Error> {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("pool.acquire");
+
+        let shared_pool = &self.0;
+
+        lazy_start(shared_pool);
+        lazy_warm_up(shared_pool);
+
+        let start = AcquireStart::now();
+        let timeout = shared_pool.statics.connection_timeout;
+
+        let elapsed = match self
+            .get_conn(&start, Some(start.at + timeout))
+            .timeout(timeout)
+            .await
+        {
+            Ok(conn) => {
+                let conn = conn?;
+                shared_pool.check_out(conn.id);
+                return Ok(PoolRef {
+                    conn: Some(conn),
+                    pool: shared_pool,
+                    ephemeral: false,
+                    reclaimed: false,
+                    #[cfg(feature = "blocking")]
+                    blocking: false,
+                    #[cfg(feature = "tracing")]
+                    span,
+                });
+            }
+            Err(e) => e,
+        };
+
+        if !shared_pool.incr_ephemeral() {
+            return Err(shared_pool.timeout_error(elapsed, &start));
+        }
+
+        let conn = match shared_pool.connect().await {
+            Ok(conn) => conn,
+            Err(e) => {
+                shared_pool.decr_ephemeral();
+                return Err(e);
+            }
+        };
+
+        Ok(PoolRef {
+            conn: Some(Conn {
+                conn,
+                birth: Instant::now(),
+                id: shared_pool.on_connect(),
+                cache_generation: shared_pool.cache_generation(),
+            }),
+            pool: shared_pool,
+            ephemeral: true,
+            reclaimed: false,
+            #[cfg(feature = "blocking")]
+            blocking: false,
+            #[cfg(feature = "tracing")]
+            span,
+        })
+    }
+
+    /// Run the pool with a closure.
+    /// Usually slightly faster than `Pool.get()` as we only do conditional broken check according to the closure result.
+    pub async fn run<T, E, F>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&mut M::Connection) -> Pin<Box<dyn Future<Output = Result<T, E>> + Send + '_>>,
+        E: From<M::Error>,
+        T: Send + 'static,
+    {
+        let conn = self.acquire().await?;
+
+        // the connection is held by a `PoolRef` while running the closure so it's still recycled by the Drop of
+        // `PoolRef` when the closure panics or this future is dropped.
+        let mut guard = PoolRef {
+            conn: Some(conn),
+            pool: &self.0,
+            ephemeral: false,
+            reclaimed: false,
+            #[cfg(feature = "blocking")]
+            blocking: false,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::none(),
+        };
+
+        let result = f(&mut *guard).await;
+
+        let mut conn = match guard.into_conn() {
+            Some(conn) => conn,
+            None => return result,
+        };
+
+        // a reclaimed connection is already reported as disconnected.
+        if !self.0.check_in(conn.id) {
+            return result;
+        }
+
+        // only check if the connection is broken when we get an error from result
+        let broken = if result.is_err() {
+            self.0.manager().is_closed(&mut conn.conn)
+        } else {
+            false
+        };
+
+        if broken {
+            self.0.on_broken(conn.id);
+            spawn_drop(&self.0);
+        } else {
+            put_back(&self.0, conn)
+        }
+        result
+    }
+
+    // use `Builder`'s connection_timeout setting to bound the whole acquire.
+    // (waiting in queue, spawning new connection and the always_check retries are all counted)
+    async fn acquire(&self) -> Result<Conn<M>, M::Error> {
+        lazy_start(&self.0);
+        lazy_warm_up(&self.0);
+
+        let start = AcquireStart::now();
+
+        // with `queue_timeout` the waiting and connecting phases are bounded separately in `get_conn`.
+        let result = if self.0.statics.queue_timeout.is_some() {
+            self.get_conn(&start, None).await
+        } else {
+            // a cold pool needs the longer budget of create_timeout for the connect to finish.
+            let connection_timeout = self.0.statics.connection_timeout;
+            let timeout = self.0.connect_timeout().max(connection_timeout);
+            let timeout = self.0.jitter(timeout);
+            self.get_conn(&start, Some(start.at + timeout))
+                .timeout(timeout)
+                .await
+                .map_err(|e| self.0.timeout_error(e, &start))
+                .and_then(|result| result)
+        };
+
+        let conn = match result {
+            Ok(conn) => conn,
+            Err(e) => {
+                if let Some(f) = self.0.statics.on_checkout_failure.as_ref() {
+                    f(&e);
+                }
+                return Err(e);
+            }
+        };
+
+        if let Some((threshold, f)) = self.0.statics.on_get_slow.as_ref() {
+            let waited = start.at.elapsed();
+            if waited > *threshold {
+                f(waited);
+            }
+        }
+
+        self.0.check_out(conn.id);
+
+        Ok(conn)
+    }
+
+    // Retry when the connection is broken(When failing the quick_check or the always_check). We exit with at most
+    // `acquire_retry_on_broken` retries and return the error of the last broken connection.
+    // `deadline` is the deadline of the whole acquire if there is one. A waiter past it is skipped when waking waiters.
+    async fn get_conn(
+        &self,
+        start: &AcquireStart,
+        deadline: Option<Instant>,
+    ) -> Result<Conn<M>, M::Error> {
+        let shared_pool = &self.0;
+        let wait_deadline = start.at + shared_pool.statics.wait_timeout;
+        let mut retry = 0;
+
+        loop {
+            let mut conn: Conn<M> = match shared_pool.statics.queue_timeout {
+                Some(queue_timeout) => {
+                    self.lock_phased(shared_pool.jitter(queue_timeout), start)
+                        .await?
+                }
+                None => {
+                    let deadline = match deadline {
+                        Some(deadline) if deadline < wait_deadline => deadline,
+                        _ => wait_deadline,
+                    };
+                    let remaining = wait_deadline.saturating_duration_since(Instant::now());
+                    shared_pool
+                        .pool_lock
+                        .lock(shared_pool, Some(deadline))
+                        .timeout(remaining)
+                        .await
+                        .map_err(|e| shared_pool.timeout_error(e, start))?
+                }
+            }
+            .into();
+
+            let result = match shared_pool.manager().quick_check(&conn.conn) {
+                Ok(()) if shared_pool.statics.always_check => {
+                    match shared_pool.check_conn(&mut conn).await {
+                        Ok(result) => result,
+                        Err(e) => {
+                            shared_pool.on_broken(conn.id);
+                            spawn_drop(shared_pool);
+                            return Err(e);
+                        }
+                    }
+                }
+                result => result,
+            };
+
+            match result {
+                Ok(()) => return Ok(conn),
+                Err(e) => {
+                    shared_pool.on_broken(conn.id);
+                    spawn_drop(shared_pool);
+                    if retry >= shared_pool.statics.acquire_retry_on_broken {
+                        return Err(e);
+                    }
+                    retry += 1;
+                }
+            }
+        }
+    }
+
+    // wait for a connection within `queue_timeout`. If we spawned a new connection in the meantime we keep waiting
+    // for it until `connection_timeout` passed since the spawn.
+    async fn lock_phased(
+        &self,
+        queue_timeout: Duration,
+        start: &AcquireStart,
+    ) -> Result<IdleConn<M>, M::Error> {
+        let shared_pool = &self.0;
+        // the deadline is extended when we enter the connecting phase so we don't record one.
+        let mut fut = shared_pool.pool_lock.lock(shared_pool, None);
+
+        let elapsed = match (&mut fut).timeout(queue_timeout).await {
+            Ok(conn) => return Ok(conn),
+            Err(e) => e,
+        };
+
+        let connect_deadline = match fut.spawned_at() {
+            Some(spawned_at) => spawned_at + shared_pool.statics.connection_timeout,
+            None => {
+                fut.set_timed_out();
+                return Err(shared_pool.timeout_error(elapsed, start));
+            }
+        };
+
+        let now = Instant::now();
+        let remaining = if connect_deadline > now {
+            connect_deadline - now
+        } else {
+            Duration::from_secs(0)
+        };
+
+        match (&mut fut).timeout(remaining).await {
+            Ok(conn) => Ok(conn),
+            Err(e) => {
+                fut.set_timed_out();
+                Err(shared_pool.timeout_error(e, start))
+            }
+        }
+    }
+
+    /// Establish one new idle connection right away if the pool has not reached `max_size`(including pending ones).
+    ///
+    /// It resolves when the connection is added to pool and the connect error is returned if it fails. It's a no-op
+    /// when the pool is full or `Builder`'s `max_pending` is reached. Useful for warming up the pool ahead of a known
+    /// spike without changing `min_idle`.
+    ///
+    /// The connect is bounded by `max_concurrent_connects` like the other spawns and it counts as a use of the pool
+    /// for `lazy_min_idle` and `start_background_tasks`.
+    pub async fn connect_now(&self) -> Result<(), M::Error> {
+        let shared_pool = &self.0;
+
+        lazy_start(shared_pool);
+        lazy_warm_up(shared_pool);
+
+        if shared_pool
+            .pool_lock
+            .incr_pending_under(shared_pool.max_size())
+        {
+            shared_pool.replenish_idle_conn(1).await
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Seed the pool with connections established outside of it.(e.g. handed off from an old pool on a deploy)
+    /// The seeded connections are treated as new born ones and are available for checkout right away.
+    ///
+    /// Connections over the `max_size`(including pending ones) are dropped. If `validate` is true each connection is
+    /// checked with `Manager::is_valid` first and dropped if it fails.
+    ///
+    /// Return the count of seeded connections.
+    pub async fn seed(&self, conns: Vec<M::Connection>, validate: bool) -> usize {
+        let shared_pool = &self.0;
+        let mut seeded = 0;
+
+        for conn in conns.into_iter() {
+            let mut conn = Conn {
+                conn,
+                birth: Instant::now(),
+                id: shared_pool.on_connect(),
+                cache_generation: shared_pool.cache_generation(),
+            };
+
+            if validate {
+                match shared_pool.check_conn(&mut conn).await {
+                    Ok(Ok(())) => {}
+                    _ => {
+                        shared_pool.on_disconnect(conn.id);
+                        continue;
+                    }
+                }
+            }
+
+            match shared_pool
+                .pool_lock
+                .put_back_seed(conn.into(), shared_pool.max_size())
+            {
+                Some(conn) => shared_pool.on_disconnect(conn.conn.id),
+                None => seeded += 1,
+            }
+        }
+
+        seeded
+    }
+
+    /// Shrink the pool to at most `max_size` connections without waiting for the reaping.
+    ///
+    /// Idle connections are dropped until the total count of connections(including pending ones) is no more than
+    /// `max_size`. The connections in use are dropped when they are returned if the pool is still over `max_size`,
+    /// and we wait until the excess ones are returned and dropped and the excess pending ones are finished. The
+    /// future never resolves if the pool stays over `max_size`.(e.g. a leaked `PoolRef`) Bound it with a timeout
+    /// in this case: the pool keeps shrinking after the future is dropped.
+    ///
+    /// The `max_size` of pool is lowered so it doesn't grow again. The `min_idle` is lowered too if it's larger than
+    /// `max_size`.
+    pub async fn shrink_to(&self, max_size: u8) {
+        let shared_pool = &self.0;
+
+        shared_pool.max_size.store(max_size, Ordering::Relaxed);
+        if shared_pool.min_idle.load(Ordering::Relaxed) > max_size {
+            shared_pool.min_idle.store(max_size, Ordering::Relaxed);
+        }
+
+        let shrunk = shared_pool.pool_lock.wait_until_size(max_size);
+        shared_pool.flush_local();
+        for conn in shared_pool.pool_lock.drop_conns_over(max_size).into_iter() {
+            shared_pool.on_shrink(conn.conn.id);
+        }
+        shrunk.await
+    }
+
+    /// Change the `idle_timeout` of pool at runtime. It takes effect from the next reaping.
+    ///
+    /// The reaping is not scheduled if both `idle_timeout` and `max_lifetime` are `None` when the pool is built so
+    /// setting one of them afterwards has no effect.
+    pub fn set_idle_timeout(&self, idle_timeout: Option<Duration>) {
+        self.0.idle_timeout.store(idle_timeout);
+    }
+
+    /// Change the `max_lifetime` of pool at runtime. It takes effect from the next reaping and the next return of a
+    /// connection.
+    ///
+    /// The same as `set_idle_timeout` the idle connections are not reaped if the reaping is not scheduled when the
+    /// pool is built.
+    pub fn set_max_lifetime(&self, max_lifetime: Option<Duration>) {
+        self.0.max_lifetime.store(max_lifetime);
+    }
+
+    /// Replace the manager of pool at runtime.(e.g. rotating the credentials of database)
+    ///
+    /// The new manager is used for the connections established from now on. The existing connections keep working
+    /// until they are recycled by `max_lifetime` or dropped, and the connects already in flight may still finish
+    /// with the old manager. `Builder`'s `socket_options` are applied to the new manager.
+    ///
+    /// The pool has no rolling replacement of the existing connections. To roll them over to the new manager lower
+    /// the `max_lifetime` with `Pool::set_max_lifetime`: the idle ones are replaced by the next reaping and the ones
+    /// in use are replaced when they are returned.
+    ///
+    /// The manager is not replaced and `ConfigError::ExecuteNotSupported` converted with `Manager::config_error` is
+    /// returned if `Builder`'s `connection_init_sql` or `validation_query` is set and the new manager doesn't support
+    /// `Manager::execute`.
+    pub fn replace_manager(&self, mut manager: M) -> Result<(), M::Error> {
+        let statics = &self.0.statics;
+        if (!statics.connection_init_sql.is_empty() || statics.validation_query.is_some())
+            && !manager.supports_execute()
+        {
+            return Err(manager.config_error(ConfigError::ExecuteNotSupported));
+        }
+        if let Some(options) = self.0.statics.socket_options.as_ref() {
+            manager.apply_socket_options(options);
+        }
+        #[cfg(test)]
+        self.0.manager_lock_uses.fetch_add(1, Ordering::Relaxed);
+
+        *self
+            .0
+            .replaced_manager
+            .write()
+            .unwrap_or_else(PoisonError::into_inner) = Some(Arc::new(manager));
+        self.0.manager_replaced.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Pause the pool. New `Pool::get` calls are parked(and can still time out) and no connection is handed out or
+    /// spawned for them until `Pool::resume` is called.
+    ///
+    /// The connections already in use are not affected and are put back to pool as usual. This is softer than
+    /// dropping the pool and can be used for a maintenance window.
+    pub fn pause(&self) {
+        self.0.pool_lock.pause();
+    }
+
+    /// Resume a paused pool and wake all the parked `Pool::get` calls.
+    pub fn resume(&self) {
+        self.0.pool_lock.resume();
+    }
+
+    /// Check if the pool is paused.
+    pub fn is_paused(&self) -> bool {
+        self.0.pool_lock.is_paused()
+    }
+
+    /// Shut down the pool. Return the count of connections forcibly closed after the `drain_timeout`.
+    ///
+    /// The pool is paused and closed so the returned connections are dropped instead of being reused. We wait for the
+    /// connections in use to be returned for at most `drain_timeout`. Then the still checked out connections are
+    /// forcibly closed: they are removed from pool and reported with `PoolEvent::Reaped` of `ReapReason::Shutdown` and
+    /// the `on_disconnect` callback, and they are dropped along with their `PoolRef`. At last all the idle
+    /// connections are closed and the pool is shrunk to 0 with `shrink_to`, waiting for the pending connections in
+    /// the rest of `drain_timeout`. The shutdown always finishes after the timeout even if some `PoolRef` is leaked.
+    ///
+    /// The pool only tracks the checkouts with `Builder::max_checkout_duration`. Without it the forcibly closed
+    /// connections are reported when their `PoolRef` is dropped.
+    ///
+    /// The pool stays paused and can't be used after the shutdown.
+    pub async fn shutdown(&self, drain_timeout: Duration) -> u8 {
+        let start = Instant::now();
+        self.pause();
+        self.0.closed.store(true, Ordering::Release);
+        let _ = timeout(drain_timeout, self.wait_until_idle()).await;
+        let forced = self.0.force_close();
+        // the idle connections are dropped in the first poll of `shrink_to` even if the timeout has passed.
+        let rest = drain_timeout
+            .checked_sub(start.elapsed())
+            .unwrap_or_else(|| Duration::from_secs(0));
+        let _ = timeout(rest, self.shrink_to(0)).await;
+        forced
+    }
+
+    /// Wait until all the connections checked out from pool are returned(or dropped).
+    ///
+    /// Useful for test teardown to make sure no `PoolRef` is alive before checking the state of pool.
+    pub async fn wait_until_idle(&self) {
+        self.0.pool_lock.wait_until_idle().await
+    }
+
+    /// Invalidate the caches of all the connections(e.g. prepared statements after a schema migration) without
+    /// recycling them. Return the new `cache_generation`.
+    ///
+    /// The pool doesn't know about the caches. The checkouts are expected to call `PoolRef.cache_invalidated` and clear
+    /// the caches of connection when it returns true.
+    pub fn invalidate_caches(&self) -> u64 {
+        self.0.cache_generation.fetch_add(1, Ordering::AcqRel) + 1
+    }
+
+    /// Return the current generation of the caches of connections. It's bumped by `invalidate_caches`.
+    pub fn cache_generation(&self) -> u64 {
+        self.0.cache_generation()
+    }
+
+    /// Subscribe to the lifecycle events of connections.(Spawned, reaped, broken and returned)
+    ///
+    /// Useful for debugging why the pool is churning connections. A slow subscriber doesn't block the pool and gets
+    /// `PoolEvent::Lagged` for the missed events instead.
+    pub fn events(&self) -> PoolEvents {
+        self.0.events.subscribe()
+    }
+
+    /// Return a state of the pool inner. This call will block the thread and wait for lock.
     pub fn state(&self) -> State {
         self.0.pool_lock.state()
     }
+
+    /// Return the ages of the pending connections. This call will block the thread and wait for lock.
+    ///
+    /// A connection pending for much longer than `connection_timeout` usually points at a network problem.
+    pub fn pending_snapshot(&self) -> Vec<Duration> {
+        self.state()
+            .pending_connections
+            .iter()
+            .map(Pending::age)
+            .collect()
+    }
+
+    /// Return a `PoolStats` of the pool inner. This call will block the thread and wait for lock.
+    ///
+    /// It's cheaper than `Pool.state()` as there is no heap allocation for the pending connections.
+    pub fn stats_snapshot(&self) -> PoolStats {
+        self.0.pool_lock.stats()
+    }
 }
 
 pub struct PoolRef<'a, M: Manager + Send> {
     conn: Option<Conn<M>>,
     pool: &'a Arc<SharedPool<M>>,
+    // true when the connection is opened by `Pool.get_ephemeral` outside the pool.
+    ephemeral: bool,
+    // true when the connection is reclaimed by `max_checkout_duration` or `Pool.shutdown` and no longer a part of
+    // pool.
+    reclaimed: bool,
+    // true when it's from `Pool.get_blocking` and can be dropped out of the context of a runtime.
+    #[cfg(feature = "blocking")]
+    blocking: bool,
+    // the span is closed when PoolRef is dropped.
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 
 impl<M: Manager + Send> Deref for PoolRef<'_, M> {
@@ -445,41 +1707,168 @@ impl<M: Manager + Send> PoolRef<'_, M> {
         &mut *self
     }
 
+    /// get the id of connection. Return None if the connection is taken.
+    pub fn connection_id(&self) -> Option<ConnectionId> {
+        self.conn.as_ref().map(|c| c.id)
+    }
+
+    /// Return a handle of the pool this connection comes from. It's a cheap clone like `Pool.clone()`.
+    ///
+    /// Useful for acquiring related connections in deep call stacks without passing the `Pool` around.
+    pub fn pool(&self) -> Pool<M> {
+        Pool(self.pool.clone())
+    }
+
+    /// Return the `Pool.cache_generation` the caches of connection(e.g. prepared statements) are built on.
+    ///
+    /// It's the generation when the connection is established or last marked by `cache_invalidated`.
+    pub fn cache_generation(&self) -> Option<u64> {
+        self.conn.as_ref().map(|c| c.cache_generation)
+    }
+
+    /// Check if the caches of connection are invalidated by `Pool.invalidate_caches` since they are built, and mark
+    /// them as up to date with the current generation.
+    ///
+    /// Return true only once for each invalidation so the caller should clear the caches of connection when it's true.
+    pub fn cache_invalidated(&mut self) -> bool {
+        let generation = self.pool.cache_generation();
+        match self.conn.as_mut() {
+            Some(conn) if conn.cache_generation < generation => {
+                conn.cache_generation = generation;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Check if the connection is an ephemeral one opened by `Pool.get_ephemeral` outside the pool.
+    pub fn is_ephemeral(&self) -> bool {
+        self.ephemeral
+    }
+
     /// take the the ownership of connection from pool and it won't be pushed back to pool anymore.
     pub fn take_conn(&mut self) -> Option<M::Connection> {
-        self.conn.take().map(|c| c.conn)
+        // a reclaimed connection is already reported as disconnected.
+        let reclaimed = !self.ephemeral && !self.check_in();
+        let pool = self.pool;
+        self.conn.take().map(|c| {
+            if !reclaimed {
+                pool.on_disconnect(c.id);
+            }
+            c.conn
+        })
+    }
+
+    /// take the ownership of connection from pool permanently and consume the `PoolRef`.
+    ///
+    /// Different from `take_conn` the connection is removed from the pool's accounting right away and new connections
+    /// are spawned to maintain the `min_idle` if needed.
+    ///
+    /// Useful for repurposing a healthy connection for long lived use(e.g. `LISTEN` of postgres).
+    pub fn detach(mut self) -> Option<M::Connection> {
+        let conn = self.take_conn();
+        if self.ephemeral {
+            self.pool.decr_ephemeral();
+        } else if !self.reclaimed {
+            spawn_drop(self.pool);
+        }
+        // close the span as it would be leaked by mem::forget.
+        #[cfg(feature = "tracing")]
+        drop(std::mem::replace(&mut self.span, tracing::Span::none()));
+        // the connection is already removed from pool so we skip the Drop of PoolRef.
+        std::mem::forget(self);
+        conn
     }
 
     /// manually push a connection to pool. We treat this connection as a new born one.
     /// operation will fail if the pool is already in full capacity(no error will return)
     pub fn push_conn(&mut self, conn: M::Connection) {
+        let _ = self.take_conn();
+        let id = self.pool.on_connect();
+        // a reclaimed slot is gone so the connection is dropped along with the `PoolRef`.
+        if !self.ephemeral && !self.reclaimed {
+            self.pool.check_out(id);
+        }
         self.conn = Some(Conn {
             conn,
             birth: Instant::now(),
+            id,
+            cache_generation: self.pool.cache_generation(),
         });
     }
-}
 
-impl<M: Manager + Send> Drop for PoolRef<'_, M> {
-    #[inline]
-    fn drop(&mut self) {
+    // take the connection and skip the Drop of `PoolRef`. The caller takes over the connection and its accounting.
+    fn into_conn(mut self) -> Option<Conn<M>> {
+        let conn = self.conn.take();
+        // close the span as it would be leaked by mem::forget.
+        #[cfg(feature = "tracing")]
+        drop(std::mem::replace(&mut self.span, tracing::Span::none()));
+        std::mem::forget(self);
+        conn
+    }
+
+    // return false if the connection is reclaimed by `max_checkout_duration` or `Pool.shutdown`. The checkout is
+    // removed otherwise.
+    fn check_in(&mut self) -> bool {
+        if !self.reclaimed {
+            if let Some(conn) = self.conn.as_ref() {
+                self.reclaimed = !self.pool.check_in(conn.id);
+            }
+        }
+        !self.reclaimed
+    }
+
+    // check in the connection and push it back to pool or close it.
+    fn release(&mut self) {
+        // ephemeral connection is not a part of pool and we just close it.
+        if self.ephemeral {
+            if let Some(conn) = self.conn.take() {
+                self.pool.on_disconnect(conn.id);
+            }
+            self.pool.decr_ephemeral();
+            return;
+        }
+
+        // the slot of a reclaimed connection is already freed and reported. We just close it.
+        if !self.check_in() {
+            return;
+        }
+
         let mut conn = match self.conn.take() {
             Some(conn) => conn,
             None => {
-                self.pool.pool_lock.decr_spawned(|_| None);
+                self.pool.pool_lock.decr_spawned(0);
                 return;
             }
         };
 
-        let broken = self.pool.manager.is_closed(&mut conn.conn);
+        // the state of connection is suspect when it's dropped by a panicking task.
+        let broken = (std::thread::panicking()
+            && self.pool.statics.return_conn_on_panic == RecyclePolicy::Broken)
+            || self.pool.manager().is_closed(&mut conn.conn);
         if broken {
+            self.pool.on_broken(conn.id);
             spawn_drop(self.pool);
         } else {
-            self.pool.pool_lock.put_back(conn.into());
+            put_back(self.pool, conn);
         };
     }
 }
 
+impl<M: Manager + Send> Drop for PoolRef<'_, M> {
+    #[inline]
+    fn drop(&mut self) {
+        #[cfg(feature = "blocking")]
+        {
+            if self.blocking {
+                return util::block_on::runtime().enter(|| self.release());
+            }
+        }
+
+        self.release();
+    }
+}
+
 // helper function to spawn drop connection and spawn new ones if needed.
 // Conn<M> should be dropped in place where spawn_drop() is used.
 fn spawn_drop<M: Manager + Send>(shared: &Arc<SharedPool<M>>) {
@@ -487,19 +1876,120 @@ fn spawn_drop<M: Manager + Send>(shared: &Arc<SharedPool<M>>) {
     shared
         .spawn(async move { shared_clone.drop_conn().await });
 //        .unwrap_or_else(|_| {
-//            shared.pool_lock.decr_spawned(|_| None);
+//            shared.pool_lock.decr_spawned(0);
 //        });
 }
 
+// helper function to spawn new connections in background.
+// the pending_count should be added to pool inner before calling this function.
+fn spawn_replenish<M: Manager + Send>(shared: &Arc<SharedPool<M>>, pending_count: u8) {
+    let shared_clone = shared.clone();
+    shared.spawn(async move { shared_clone.replenish_idle_conn(pending_count).await });
+}
+
+// spawn the background tasks of pool(reaper, replenish and etc). They are only started once.
+fn start_background_tasks<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
+    if shared_pool.started.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    schedule_reaping(shared_pool);
+    schedule_reclaim(shared_pool);
+    schedule_health_check(shared_pool);
+    schedule_replenish(shared_pool);
+    schedule_stats(shared_pool);
+    garbage_collect(shared_pool);
+}
+
+// start the background tasks and spawn the min_idle connections on the first use of pool when
+// `start_background_tasks` is false.
+fn lazy_start<M: Manager + Send>(shared: &Arc<SharedPool<M>>) {
+    if shared.statics.start_background_tasks || shared.started.load(Ordering::Acquire) {
+        return;
+    }
+
+    start_background_tasks(shared);
+
+    if let Some(pending_count) = shared.pool_lock.incr_pending_to(shared.min_idle()) {
+        spawn_replenish(shared, pending_count);
+    }
+}
+
+// spawn the min_idle connections on the first use of pool when `lazy_min_idle` is set.
+fn lazy_warm_up<M: Manager + Send>(shared: &Arc<SharedPool<M>>) {
+    if shared.warmed.load(Ordering::Acquire) || shared.warmed.swap(true, Ordering::AcqRel) {
+        return;
+    }
+
+    let min_idle = shared.min_idle();
+    if let Some(pending_count) = shared.pool_lock.incr_pending_to(min_idle) {
+        spawn_replenish(shared, pending_count);
+    }
+}
+
+// helper function to put back a connection to pool.
+// When `test_on_return` is set the connection is checked in a spawned future and dropped if it fails the check.
+fn put_back<M: Manager + Send>(shared: &Arc<SharedPool<M>>, mut conn: Conn<M>) {
+    // a connection reached max_lifetime while checked out is closed instead of going back to pool.
+    if shared.lifetime_passed(&conn, Instant::now()) {
+        shared.on_expire(conn.id);
+        drop(conn);
+        spawn_drop(shared);
+        return;
+    }
+
+    if !shared.statics.test_on_return {
+        shared.put_back(conn);
+        schedule_cooldown(shared);
+        return;
+    }
+
+    let shared_clone = shared.clone();
+    shared.spawn(async move {
+        match shared_clone.check_conn(&mut conn).await {
+            Ok(Ok(())) => {
+                shared_clone.put_back(conn);
+                schedule_cooldown(&shared_clone);
+            }
+            _ => {
+                shared_clone.on_broken(conn.id);
+                drop(conn);
+                let _ = shared_clone.drop_conn().await;
+            }
+        }
+    });
+}
+
+// wake a waiter when the `reuse_cooldown` of a returned connection elapses. It would keep waiting otherwise as the
+// connection is skipped when it's put back.
+fn schedule_cooldown<M: Manager + Send>(shared: &Arc<SharedPool<M>>) {
+    if let Some(cooldown) = shared.statics.reuse_cooldown {
+        let shared_clone = shared.clone();
+        shared.spawn(async move {
+            delay_for(cooldown).await;
+            shared_clone.pool_lock.wake_one();
+        });
+    }
+}
+
+// return a random number in [0, 1]. `RandomState` is randomly seeded so it's good enough to spread the timers.
+fn random() -> f64 {
+    RandomState::new().build_hasher().finish() as f64 / u64::MAX as f64
+}
+
 // schedule reaping runs in a spawned future.
 fn schedule_reaping<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
     let statics = &shared_pool.statics;
     if statics.max_lifetime.is_some() || statics.idle_timeout.is_some() {
         let shared_clone = shared_pool.clone();
         let mut interval = interval(statics.reaper_rate);
+        let reaper_jitter = statics.reaper_jitter;
         let fut = async move {
             loop {
                 let _i = interval.tick().await;
+                if reaper_jitter != Duration::from_secs(0) {
+                    delay_for(reaper_jitter.mul_f64(random())).await;
+                }
                 let _ = shared_clone.reap_idle_conn().await;
             }
         };
@@ -508,6 +1998,93 @@ fn schedule_reaping<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
     }
 }
 
+// schedule the reclaim of connections held longer than `max_checkout_duration` in a spawned future.
+fn schedule_reclaim<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
+    let statics = &shared_pool.statics;
+    if let Some(max_checkout_duration) = statics.max_checkout_duration {
+        let shared_clone = shared_pool.clone();
+        let mut interval = interval(statics.reaper_rate.min(max_checkout_duration));
+        let fut = async move {
+            loop {
+                let _i = interval.tick().await;
+                for id in shared_clone.take_overdue(max_checkout_duration).into_iter() {
+                    shared_clone.on_broken(id);
+                    spawn_drop(&shared_clone);
+                }
+            }
+        };
+        shared_pool.spawn(fut);
+    }
+}
+
+// schedule the background health check of idle connections in a spawned future.
+fn schedule_health_check<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
+    if let Some(health_check_interval) = shared_pool.statics.health_check_interval {
+        let shared_clone = shared_pool.clone();
+        let mut interval = interval(health_check_interval);
+        let fut = async move {
+            loop {
+                let _i = interval.tick().await;
+                shared_clone.health_check_idle().await;
+            }
+        };
+        shared_pool.spawn(fut);
+    }
+}
+
+// schedule the `on_stats` callback runs in a spawned future.
+fn schedule_stats<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
+    let statics = &shared_pool.statics;
+    if let Some(f) = statics.on_stats.clone() {
+        let shared_clone = shared_pool.clone();
+        let mut interval = interval(statics.stats_interval);
+        let fut = async move {
+            loop {
+                let _i = interval.tick().await;
+                let state = shared_clone.pool_lock.state();
+                f(&state);
+            }
+        };
+        shared_pool.spawn(fut);
+    }
+}
+
+// the count of reaper_rate ticks `adaptive_min_idle` tracks the peak demand over.
+const ADAPTIVE_WINDOW: usize = 4;
+
+// schedule min_idle maintain runs in a spawned future.
+// It's independent from the reaping so that a pool dropped to zero because of failed connections can still recover.
+fn schedule_replenish<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
+    let statics = &shared_pool.statics;
+    let adaptive = statics.adaptive_min_idle;
+    if (statics.min_idle > 0 || adaptive.is_some()) && !statics.spawn_on_acquire_only {
+        let shared_clone = shared_pool.clone();
+        let mut interval = interval(statics.reaper_rate);
+        let fut = async move {
+            // the peaks of connections in use of the recent ticks.
+            let mut peaks = VecDeque::with_capacity(ADAPTIVE_WINDOW);
+            loop {
+                let _i = interval.tick().await;
+                if let Some((floor, ceil)) = adaptive {
+                    if peaks.len() == ADAPTIVE_WINDOW {
+                        peaks.pop_front();
+                    }
+                    peaks.push_back(shared_clone.pool_lock.take_peak_in_use());
+                    let peak = peaks.iter().cloned().max().unwrap_or(0);
+                    let target = ((peak as u16 + 1) / 2) as u8;
+                    let target = target.max(floor).min(ceil).min(shared_clone.max_size());
+                    shared_clone.min_idle.store(target, Ordering::Relaxed);
+                }
+                let min_idle = shared_clone.min_idle();
+                if let Some(pending_new) = shared_clone.pool_lock.incr_pending_to(min_idle) {
+                    let _ = shared_clone.replenish_idle_conn(pending_new).await;
+                }
+            }
+        };
+        shared_pool.spawn(fut);
+    }
+}
+
 // schedule garbage collection runs in a spawned future.
 fn garbage_collect<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
     let statics = &shared_pool.statics;
@@ -517,7 +2094,7 @@ fn garbage_collect<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
         let fut = async move {
             loop {
                 let _i = interval.tick().await;
-                let _ = shared_clone.garbage_collect();
+                shared_clone.garbage_collect();
             }
         };
         shared_pool.spawn(fut);
@@ -526,6 +2103,8 @@ fn garbage_collect<M: Manager + Send>(shared_pool: &Arc<SharedPool<M>>) {
 }
 
 // a shortcut for tokio timeout
+// the inner future is always polled before the timer so a connection becoming available right at the deadline wins
+// over the timeout. A woken `PoolLockFuture` never drops the connection it could take in the same poll.
 trait CrateTimeOut: Sized + Future {
     fn timeout(self, dur: std::time::Duration) -> Timeout<Self> {
         timeout(dur, self)
@@ -1,10 +1,91 @@
 use std::fmt::Debug;
 use std::future::Future;
 use std::pin::Pin;
+use std::time::Duration;
+
+use crate::builder::ConfigError;
+use crate::pool_inner::TimeoutInfo;
 
 pub(crate) type ManagerFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
 /// trait come from bb8.
+///
+/// # Composite connection:
+/// `Connection` can be a composite of multiple connections that must be acquired together. (e.g. a tuple of a sql
+/// connection and a cache connection)
+///
+/// The pool always treats the `Connection` as one unit:
+/// - `connect` should establish all parts and it's counted as one spawned connection of the pool.
+/// - when `is_valid` returns an error or `is_closed` returns true the whole unit is dropped and replaced.
+///
+/// So `is_valid` and `is_closed` should check all the parts and fail if any of them is bad.
+///
+/// ```ignore
+/// impl Manager for CompositeManager {
+///     type Connection = (SqlConnection, CacheConnection);
+///     type Error = CompositeError;
+///
+///     fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+///         Box::pin(async move {
+///             let sql = self.sql.connect().await?;
+///             let cache = self.cache.connect().await?;
+///             Ok((sql, cache))
+///         })
+///     }
+///
+///     fn is_valid<'a>(
+///         &'a self,
+///         (sql, cache): &'a mut Self::Connection,
+///     ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+///         Box::pin(async move {
+///             sql.ping().await?;
+///             cache.ping().await?;
+///             Ok(())
+///         })
+///     }
+///
+///     fn is_closed(&self, (sql, cache): &mut Self::Connection) -> bool {
+///         sql.is_closed() || cache.is_closed()
+///     }
+/// }
+/// ```
+///
+/// # Boxed connection:
+/// `Connection` only needs to be `Send + 'static`, so a boxed trait object works for swapping backends at runtime.
+/// The box is moved around by the pool as is and `PoolRef` derefs to the box.
+///
+/// ```ignore
+/// trait DbConn: Send {
+///     fn ping(&mut self) -> Pin<Box<dyn Future<Output = Result<(), DbError>> + Send + '_>>;
+///     fn is_closed(&self) -> bool;
+/// }
+///
+/// impl Manager for DynManager {
+///     type Connection = Box<dyn DbConn>;
+///     type Error = DbError;
+///
+///     fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+///         Box::pin(async move {
+///             let conn: Box<dyn DbConn> = match self.backend {
+///                 Backend::A => Box::new(ConnA::connect().await?),
+///                 Backend::B => Box::new(ConnB::connect().await?),
+///             };
+///             Ok(conn)
+///         })
+///     }
+///
+///     fn is_valid<'a>(
+///         &'a self,
+///         conn: &'a mut Self::Connection,
+///     ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+///         conn.ping()
+///     }
+///
+///     fn is_closed(&self, conn: &mut Self::Connection) -> bool {
+///         conn.is_closed()
+///     }
+/// }
+/// ```
 pub trait Manager: Send + Sync + 'static {
     type Connection: Send + 'static;
     type Error: Send + 'static + Debug + From<tokio::time::Elapsed>;
@@ -17,4 +98,96 @@ pub trait Manager: Send + Sync + 'static {
     ) -> ManagerFuture<'a, Result<(), Self::Error>>;
 
     fn is_closed(&self, conn: &mut Self::Connection) -> bool;
+
+    /// A cheap liveness check of an idle connection before it's handed out.(e.g. a cached error flag of the
+    /// connection) A connection fails the check is dropped and replaced. The replacing counts as a retry of
+    /// `Builder`'s `acquire_retry_on_broken` setting and the error is returned when the retries are used up.
+    ///
+    /// Different from `is_valid` it runs on every checkout so it must not do any I/O.
+    ///
+    /// Default to pass all the connections.
+    fn quick_check(&self, _conn: &Self::Connection) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Execute a sql statement on the connection. It's used by `Builder`'s `connection_init_sql` and
+    /// `validation_query` settings.
+    ///
+    /// Only SQL backends need to implement it and they must return true from `supports_execute` too. Default to ignore
+    /// the statement.
+    fn execute<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+        _sql: &'a str,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    /// If the manager implements `execute`. Building a pool with `connection_init_sql` or `validation_query` set
+    /// returns `ConfigError::ExecuteNotSupported` when it returns false, as the statements would be silently ignored.
+    ///
+    /// Default false.
+    fn supports_execute(&self) -> bool {
+        false
+    }
+
+    /// Convert a timeout of acquiring connection from pool into `Self::Error`.
+    ///
+    /// `info` contains the waited duration and the state of pool at the time so the error can tell why it happened.
+    /// (e.g. all connections are in use and many callers are waiting means the pool is undersized)
+    ///
+    /// Default to convert the `Elapsed` with `From` trait.
+    fn timeout_error(&self, elapsed: tokio::time::Elapsed, _info: TimeoutInfo) -> Self::Error {
+        elapsed.into()
+    }
+
+    /// Convert an invalid setting of `Builder` found when building the pool into `Self::Error`.
+    ///
+    /// # Panics:
+    /// The default implementation panics with the `ConfigError` as there is no way to convert it without knowing
+    /// `Self::Error`. Override it to get the invalid setting returned from `Builder::build` as an error.
+    fn config_error(&self, error: ConfigError) -> Self::Error {
+        panic!("invalid settings of Builder: {}", error)
+    }
+
+    /// Apply the socket options set with `Builder`'s `socket_options` setting. It's called once when the pool is
+    /// built and before any `connect`, so the options are applied consistently to all the connections.
+    ///
+    /// Default to ignore the options.
+    fn apply_socket_options(&mut self, _options: &SocketOptions) {}
+
+    /// Report the activity of a connection since the last call. It's called when the connection is returned to pool
+    /// and the result is summed into `State.total_queries` and `State.total_bytes`.
+    ///
+    /// Default to report no activity.
+    fn stats(&self, _conn: &mut Self::Connection) -> ConnectionStats {
+        ConnectionStats::default()
+    }
+}
+
+/// The socket options of connections passed to `Manager::apply_socket_options`. `None` means the backend default.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct SocketOptions {
+    /// The idle time before TCP keepalive probes are sent. `Some` enables keepalive.
+    pub keepalive: Option<Duration>,
+    pub nodelay: Option<bool>,
+    pub reuse_address: Option<bool>,
+}
+
+/// The activity of a connection reported by `Manager::stats`.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ConnectionStats {
+    pub queries: u64,
+    pub bytes: u64,
+}
+
+/// A `Manager` that can take a connection level TLS config. Used by `Builder::build_with_tls` to pass the config
+/// through the pool builder.
+///
+/// The config is used by every following `Manager::connect` of the returned manager.
+pub trait WithTls<Tls> {
+    /// The `Manager` using the given TLS config.
+    type Output: Manager;
+
+    fn with_tls(self, tls: Tls) -> Self::Output;
 }
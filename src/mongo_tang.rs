@@ -4,6 +4,7 @@ use std::pin::Pin;
 
 use mongodb::{Client, Error, ThreadedClient};
 
+use crate::builder::ConfigError;
 use crate::manager::{Manager, ManagerFuture};
 
 pub struct MongoManager {
@@ -45,6 +46,10 @@ impl Manager for MongoManager {
     fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
         false
     }
+
+    fn config_error(&self, error: ConfigError) -> Self::Error {
+        MongoPoolError::Config(error)
+    }
 }
 
 impl fmt::Debug for MongoManager {
@@ -63,6 +68,8 @@ impl fmt::Debug for MongoManager {
 pub enum MongoPoolError {
     Inner(Error),
     TimeOut,
+    /// An invalid setting of `Builder` found when building the pool.
+    Config(ConfigError),
 }
 
 impl fmt::Debug for MongoPoolError {
@@ -73,6 +80,9 @@ impl fmt::Debug for MongoPoolError {
                 .debug_struct("MongoPoolError")
                 .field("source", &"Connection Timeout")
                 .finish(),
+            MongoPoolError::Config(e) => {
+                f.debug_struct("MongoPoolError").field("source", e).finish()
+            }
         }
     }
 }
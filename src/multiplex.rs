@@ -0,0 +1,312 @@
+use std::future::Future;
+use std::num::NonZeroUsize;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::time::timeout;
+
+use crate::manager::Manager;
+use crate::util::linked_list::WakerList;
+
+/// A `Manager` of connections supporting multiple concurrent streams.(e.g. HTTP/2 or some database proxies)
+///
+/// The connection is shared by the streams so it must be `Sync`. It's used by `MultiplexPool` which hands out
+/// `StreamPermit` of a connection instead of exclusive ownership.
+pub trait MultiplexManager: Manager
+where
+    Self::Connection: Sync,
+{
+    /// The max count of concurrent streams of one connection.
+    fn max_concurrency_per_connection(&self) -> usize;
+}
+
+/// A pool of multiplexed connections built with `Builder::build_multiplex`.
+///
+/// A `StreamPermit` is handed out from the least busy connection with free streams. A new connection is only
+/// established when all the connections are at `max_concurrency_per_connection`, until `max_size` of `Builder`.
+/// After that the acquires wait for a permit to be dropped.
+///
+/// `MultiplexPool` is cheap to clone and all the clones share the same connections.
+pub struct MultiplexPool<M: MultiplexManager>
+where
+    M::Connection: Sync,
+{
+    inner: Arc<MultiplexInner<M>>,
+}
+
+impl<M: MultiplexManager> Clone for MultiplexPool<M>
+where
+    M::Connection: Sync,
+{
+    fn clone(&self) -> Self {
+        MultiplexPool {
+            inner: self.inner.clone(),
+        }
+    }
+}
+
+struct MultiplexInner<M: MultiplexManager>
+where
+    M::Connection: Sync,
+{
+    manager: M,
+    max_size: u8,
+    connection_timeout: Duration,
+    state: Mutex<MultiplexState<M>>,
+}
+
+struct MultiplexState<M: Manager> {
+    // the connections and the count of streams in use of them.
+    conns: Vec<(Arc<M::Connection>, usize)>,
+    pending: u8,
+    // the acquires waiting for a free stream in order.
+    waiters: WakerList,
+}
+
+// the result of a `Reserve`.
+enum Reserved<M: Manager> {
+    // a stream of an established connection.
+    Stream(Arc<M::Connection>),
+    // a pending connection is added for us to establish.
+    Connect,
+}
+
+impl<M: MultiplexManager> MultiplexPool<M>
+where
+    M::Connection: Sync,
+{
+    pub(crate) fn new(manager: M, max_size: u8, connection_timeout: Duration) -> Self {
+        MultiplexPool {
+            inner: Arc::new(MultiplexInner {
+                manager,
+                max_size,
+                connection_timeout,
+                state: Mutex::new(MultiplexState {
+                    conns: Vec::with_capacity(max_size as usize),
+                    pending: 0,
+                    waiters: WakerList::new(),
+                }),
+            }),
+        }
+    }
+
+    /// Acquire a stream of a connection. The whole acquire is bounded by `connection_timeout` of `Builder`.
+    pub async fn get(&self) -> Result<StreamPermit<M>, M::Error> {
+        let inner = &self.inner;
+        timeout(inner.connection_timeout, self.acquire()).await?
+    }
+
+    /// Return the count of connections and the count of streams in use.
+    pub fn state(&self) -> (usize, usize) {
+        let state = self.inner.lock();
+        let streams = state.conns.iter().map(|(_, streams)| *streams).sum();
+        (state.conns.len(), streams)
+    }
+
+    async fn acquire(&self) -> Result<StreamPermit<M>, M::Error> {
+        let reserve = Reserve {
+            pool: &self.inner,
+            wait_key: None,
+        };
+        let conn = match reserve.await {
+            Reserved::Stream(conn) => conn,
+            Reserved::Connect => self.connect().await?,
+        };
+
+        Ok(StreamPermit {
+            conn: Some(conn),
+            pool: self.inner.clone(),
+        })
+    }
+
+    // establish a new connection and take the first stream of it.
+    async fn connect(&self) -> Result<Arc<M::Connection>, M::Error> {
+        // the pending is removed if the connect fails or this future is dropped.
+        let guard = PendingGuard { pool: &self.inner };
+        let conn = Arc::new(self.inner.manager.connect().await?);
+        std::mem::forget(guard);
+
+        let waiters = {
+            let mut state = self.inner.lock();
+            state.pending -= 1;
+            state.conns.push((conn.clone(), 1));
+            // the new connection has free streams for all the waiters.
+            state.waiters.drain_all()
+        };
+        for waker in waiters.into_iter() {
+            waker.wake();
+        }
+
+        Ok(conn)
+    }
+}
+
+impl<M: MultiplexManager> MultiplexInner<M>
+where
+    M::Connection: Sync,
+{
+    fn lock(&self) -> MutexGuard<'_, MultiplexState<M>> {
+        self.state.lock().unwrap()
+    }
+
+    // wake the first waiter not waken yet.
+    fn wake_one(&self, mut state: MutexGuard<'_, MultiplexState<M>>) {
+        let waker = state.waiters.wake_one_weak();
+        drop(state);
+        if let Some(waker) = waker {
+            waker.wake();
+        }
+    }
+}
+
+struct PendingGuard<'a, M: MultiplexManager>
+where
+    M::Connection: Sync,
+{
+    pool: &'a MultiplexInner<M>,
+}
+
+impl<M: MultiplexManager> Drop for PendingGuard<'_, M>
+where
+    M::Connection: Sync,
+{
+    fn drop(&mut self) {
+        let mut state = self.pool.lock();
+        state.pending -= 1;
+        self.pool.wake_one(state);
+    }
+}
+
+// reserve a stream of the least busy connection or a pending connection. Park if we can't do either.
+struct Reserve<'a, M: MultiplexManager>
+where
+    M::Connection: Sync,
+{
+    pool: &'a MultiplexInner<M>,
+    wait_key: Option<NonZeroUsize>,
+}
+
+impl<M: MultiplexManager> Drop for Reserve<'_, M>
+where
+    M::Connection: Sync,
+{
+    fn drop(&mut self) {
+        if let Some(wait_key) = self.wait_key.take() {
+            let mut state = self.pool.lock();
+            let waker = unsafe { state.waiters.remove(wait_key) };
+            // we were waken but are dropped before taking the stream. Pass the wake to the next waiter.
+            if waker.is_none() {
+                self.pool.wake_one(state);
+            }
+        }
+    }
+}
+
+impl<M: MultiplexManager> Future for Reserve<'_, M>
+where
+    M::Connection: Sync,
+{
+    type Output = Reserved<M>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let pool = self.pool;
+        let max_concurrency = pool.manager.max_concurrency_per_connection();
+        let mut state = pool.lock();
+
+        let can_connect = state.conns.len() + (state.pending as usize) < pool.max_size as usize;
+        let least_busy = state
+            .conns
+            .iter_mut()
+            .filter(|(_, streams)| *streams < max_concurrency)
+            .min_by_key(|(_, streams)| *streams);
+
+        let reserved = match least_busy {
+            Some((conn, streams)) => {
+                *streams += 1;
+                Some(Reserved::Stream(conn.clone()))
+            }
+            None if can_connect => {
+                state.pending += 1;
+                Some(Reserved::Connect)
+            }
+            None => None,
+        };
+
+        match (reserved, self.wait_key) {
+            (Some(reserved), wait_key) => {
+                if let Some(wait_key) = wait_key {
+                    unsafe { state.waiters.remove(wait_key) };
+                    self.wait_key = None;
+                }
+                Poll::Ready(reserved)
+            }
+            (None, Some(wait_key)) => {
+                // the waker is taken when we are waken or it could be changed since the last poll.
+                let opt = unsafe { state.waiters.get(wait_key) };
+                *opt = Some(cx.waker().clone());
+                Poll::Pending
+            }
+            (None, None) => {
+                let (wait_key, _) = state.waiters.insert(Some(cx.waker().clone()), None);
+                self.wait_key = Some(wait_key);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+/// A stream of a multiplexed connection. The stream is returned to the connection when the permit is dropped.
+pub struct StreamPermit<M: MultiplexManager>
+where
+    M::Connection: Sync,
+{
+    conn: Option<Arc<M::Connection>>,
+    pool: Arc<MultiplexInner<M>>,
+}
+
+impl<M: MultiplexManager> Deref for StreamPermit<M>
+where
+    M::Connection: Sync,
+{
+    type Target = M::Connection;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn
+            .as_ref()
+            .expect("Connection has already been taken")
+    }
+}
+
+impl<M: MultiplexManager> Drop for StreamPermit<M>
+where
+    M::Connection: Sync,
+{
+    fn drop(&mut self) {
+        let conn = match self.conn.take() {
+            Some(conn) => conn,
+            None => return,
+        };
+
+        let mut state = self.pool.lock();
+        let index = match state.conns.iter().position(|(c, _)| Arc::ptr_eq(c, &conn)) {
+            Some(index) => index,
+            None => return,
+        };
+        drop(conn);
+
+        state.conns[index].1 -= 1;
+        // the last stream is returned and we own the connection. Check if it's closed and drop it if so.
+        if state.conns[index].1 == 0 {
+            if let Some(conn) = Arc::get_mut(&mut state.conns[index].0) {
+                if self.pool.manager.is_closed(conn) {
+                    state.conns.swap_remove(index);
+                }
+            }
+        }
+
+        self.pool.wake_one(state);
+    }
+}
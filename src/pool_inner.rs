@@ -1,13 +1,25 @@
+use std::collections::hash_map::DefaultHasher;
 use std::collections::VecDeque;
 use std::fmt;
 use std::future::Future;
+use std::hash::{Hash, Hasher};
 use std::num::NonZeroUsize;
+use std::ops::{Deref, DerefMut};
 use std::pin::Pin;
-use std::sync::{Arc, Mutex, MutexGuard};
+use std::sync::atomic::{AtomicBool, AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
 use std::task::{Context, Poll, Waker};
+use std::thread::{self, ThreadId};
 use std::time::{Duration, Instant};
 
-use crate::{manager::Manager, util::linked_list::WakerList, IdleConn, SharedPool};
+use tokio::time::delay_for;
+
+use crate::{
+    builder::{Builder, EventCallback, PanicPolicy, WaitStrategy},
+    manager::{ConnectionStats, Manager},
+    util::linked_list::WakerList,
+    IdleConn, SharedPool, WaitId, WaitOutcome,
+};
 
 #[derive(Debug, Clone)]
 pub struct Pending {
@@ -21,6 +33,11 @@ impl Pending {
         }
     }
 
+    /// Return the time elapsed since the connection started establishing.
+    pub fn age(&self) -> Duration {
+        self.start_from.elapsed()
+    }
+
     pub(crate) fn should_remove(&self, connection_timeout: Duration) -> bool {
         Instant::now() > (self.start_from + connection_timeout * 6)
     }
@@ -33,13 +50,186 @@ pub(crate) struct PoolInner<M: Manager> {
     pending: VecDeque<Pending>,
     conn: VecDeque<IdleConn<M>>,
     waiters: WakerList,
+    // true when a waiter parked because we hit the max pool size. reset when the pool is not saturated anymore.
+    at_capacity: bool,
+    // true when the pool ran out of idle connections at max size. reset when a connection is put back to idle.
+    empty: bool,
+    // true when the pool is paused. new waiters are parked and no connection is handed out until resumed.
+    paused: bool,
+    // the max count of pending connections. None is unbounded.
+    max_pending: Option<u8>,
+    // the size of the most recently used region at the front of `conn`. 0 disables it.
+    mru_size: u8,
+    // the count of connections currently in the most recently used region.
+    mru_len: u8,
+    // wakers of `Pool.wait_until_idle` calls. They are waken when no connection is in use.
+    idle_waiters: WakerList,
+    // wakers of `Pool.shrink_to` calls. They are waken when the count of connections could have dropped.
+    shrink_waiters: WakerList,
+    total_queries: u64,
+    total_bytes: u64,
+    #[cfg(feature = "metrics")]
+    queue_wait: Average,
+    #[cfg(feature = "metrics")]
+    connect_time: Average,
+    // the count of reaper passes that scanned the idle connections.
+    #[cfg(test)]
+    reap_scans: usize,
+}
+
+// the average of durations recorded.
+#[cfg(feature = "metrics")]
+#[derive(Default)]
+struct Average {
+    total: Duration,
+    count: u32,
+}
+
+#[cfg(feature = "metrics")]
+impl Average {
+    fn record(&mut self, duration: Duration) {
+        // restart from the current average when the count overflows.
+        if self.count == u32::MAX {
+            self.total = self.get();
+            self.count = 1;
+        }
+        self.total += duration;
+        self.count += 1;
+    }
+
+    fn get(&self) -> Duration {
+        if self.count == 0 {
+            Duration::from_secs(0)
+        } else {
+            self.total / self.count
+        }
+    }
 }
 
 impl<M: Manager> PoolInner<M> {
+    // the idle connections are kept in `conn` as [most recently used region(LIFO)][the rest(FIFO)].
+    // A returned connection goes to the front and the oldest of the region overflows to the back.
+    fn push_returned(&mut self, conn: IdleConn<M>) {
+        if self.mru_size == 0 {
+            self.conn.push_back(conn);
+            return;
+        }
+        self.conn.push_front(conn);
+        if self.mru_len < self.mru_size {
+            self.mru_len += 1;
+        } else if let Some(overflow) = self.conn.remove(self.mru_size as usize) {
+            self.conn.push_back(overflow);
+        }
+    }
+
+    fn pop_idle(&mut self) -> Option<IdleConn<M>> {
+        let conn = self.conn.pop_front();
+        if conn.is_some() && self.mru_len != 0 {
+            self.mru_len -= 1;
+        }
+        conn
+    }
+
+    // pop the first idle connection past its `reuse_cooldown`.
+    fn pop_ready(&mut self) -> Option<IdleConn<M>> {
+        match self.conn.front() {
+            Some(conn) if conn.not_before.is_none() => self.pop_idle(),
+            Some(_) => {
+                let now = Instant::now();
+                let index = self
+                    .conn
+                    .iter()
+                    .position(|conn| conn.not_before.map(|t| t <= now).unwrap_or(true))?;
+                self.remove_idle(index)
+            }
+            None => None,
+        }
+    }
+
+    fn remove_idle(&mut self, index: usize) -> Option<IdleConn<M>> {
+        let conn = self.conn.remove(index);
+        if conn.is_some() && index < self.mru_len as usize {
+            self.mru_len -= 1;
+        }
+        conn
+    }
+
+    // the connections cached by threads are counted as in use here. See `PoolLock::in_use`.
+    fn in_use(&self) -> u8 {
+        self.spawned.saturating_sub(self.conn.len() as u8)
+    }
+
+    // take the wakers of `Pool.wait_until_idle` calls if no connection is in use. The wakers of `Pool.shrink_to`
+    // calls are always taken so they check the count of connections again.
+    fn take_idle_waiters(&mut self, local_idle: u8) -> Vec<Waker> {
+        let mut wakers = self.shrink_waiters.drain_all();
+        if self.in_use().saturating_sub(local_idle) == 0 {
+            wakers.extend(self.idle_waiters.drain_all());
+        }
+        wakers
+    }
+
+    // don't wake any waiter when paused. They would park again anyway.
+    fn wake_one_unpaused(&mut self) -> Option<Waker> {
+        if self.paused {
+            None
+        } else {
+            self.waiters.wake_one_weak()
+        }
+    }
+
     fn decr_spawned_inner(&mut self) {
         if self.spawned != 0 {
             self.spawned -= 1;
         }
+        self.at_capacity = false;
+    }
+
+    // drop the idle connections matching should_drop and return new pending count as Some(u8).
+    fn drop_conns<F>(
+        &mut self,
+        min_idle: u8,
+        max_drop: Option<u8>,
+        dropped: &mut Vec<IdleConn<M>>,
+        mut should_drop: F,
+    ) -> Option<u8>
+    where
+        F: FnMut(&IdleConn<M>) -> bool,
+    {
+        // nothing to drop or replenish so we skip the scan.
+        if self.conn.is_empty() && self.total() >= min_idle {
+            return None;
+        }
+
+        #[cfg(test)]
+        {
+            self.reap_scans += 1;
+        }
+
+        let mut dropped_count = 0u8;
+        let mut index = 0;
+        while let Some(conn) = self.conn.get(index) {
+            if max_drop.map(|max| dropped_count >= max).unwrap_or(false) {
+                break;
+            }
+            if should_drop(conn) {
+                if let Some(conn) = self.remove_idle(index) {
+                    dropped.push(conn);
+                }
+                self.decr_spawned_inner();
+                dropped_count += 1;
+            } else {
+                index += 1;
+            }
+        }
+
+        match self.needed_pending(min_idle) {
+            0 => None,
+            pending_new => {
+                self.incr_pending_inner(pending_new);
+                Some(pending_new)
+            }
+        }
     }
 
     fn decr_pending_inner(&mut self, count: u8) {
@@ -48,74 +238,531 @@ impl<M: Manager> PoolInner<M> {
         }
     }
 
+    // panic if the bookkeeping of pool is broken. Only compiled with `fairness-check` feature in debug builds.
+    #[inline]
+    fn check_invariants(&self) {
+        #[cfg(all(debug_assertions, feature = "fairness-check"))]
+        {
+            self.waiters.check_invariants();
+            assert!(
+                self.spawned as usize + self.pending.len() <= u8::MAX as usize,
+                "PoolInner: spawned + pending overflows the total"
+            );
+            assert!(
+                self.conn.len() <= self.spawned as usize,
+                "PoolInner: more idle connections than spawned ones"
+            );
+            assert!(
+                self.mru_len <= self.mru_size && self.mru_len as usize <= self.conn.len(),
+                "PoolInner: mru_len is out of the idle connections"
+            );
+        }
+    }
+
     #[inline]
-    fn total(&mut self) -> u8 {
+    fn total(&self) -> u8 {
         self.spawned + self.pending.len() as u8
     }
 
+    // the count of new pending connections needed to reach min_idle. The pending ones in flight are counted so
+    // the warmup, reaper and replenish never over spawn when they run concurrently.
+    fn needed_pending(&self, min_idle: u8) -> u8 {
+        let total_now = self.total();
+        if total_now < min_idle {
+            self.cap_pending(min_idle - total_now)
+        } else {
+            0
+        }
+    }
+
+    // cap the count of new pending connections so the total pending is no more than max_pending.
+    fn cap_pending(&self, count: u8) -> u8 {
+        match self.max_pending {
+            Some(max) => count.min(max.saturating_sub(self.pending.len() as u8)),
+            None => count,
+        }
+    }
+
     fn incr_pending_inner(&mut self, count: u8) {
         for _i in 0..count {
             self.pending.push_back(Pending::new());
         }
     }
+
+    fn stats(&self, local_idle: u8) -> PoolStats {
+        let idle = self.conn.len() as u8 + local_idle;
+        PoolStats {
+            connections: self.spawned,
+            idle_connections: idle,
+            in_use: self.spawned.saturating_sub(idle),
+            waiting: self.waiters.len(),
+            pending_connections: self.pending.len() as u8,
+        }
+    }
+
+    fn state(&self, local_idle: u8) -> State {
+        State {
+            connections: self.spawned,
+            idle_connections: self.conn.len() as u8 + local_idle,
+            pending_connections: self.pending.iter().cloned().collect(),
+            waiting: self.waiters.len(),
+            total_queries: self.total_queries,
+            total_bytes: self.total_bytes,
+            #[cfg(feature = "metrics")]
+            avg_queue_wait: self.queue_wait.get(),
+            #[cfg(feature = "metrics")]
+            avg_connect_time: self.connect_time.get(),
+        }
+    }
+}
+
+// the slot of a thread in the thread local cache. See `Builder::thread_local_cache`.
+struct LocalSlot<M: Manager> {
+    conn: Option<IdleConn<M>>,
+    // the thread cached `conn`. Only it can take the connection from the slot.
+    owner: Option<ThreadId>,
+    // the stats of connections returned to this slot. They are summed into `PoolInner` when the slot is flushed.
+    stats: ConnectionStats,
+}
+
+thread_local! {
+    // the id of current thread and the hash of it picking the slot of thread local cache.
+    static THREAD_KEY: (ThreadId, usize) = {
+        let id = thread::current().id();
+        let mut hasher = DefaultHasher::new();
+        id.hash(&mut hasher);
+        (id, hasher.finish() as usize)
+    };
 }
 
 pub(crate) struct PoolLock<M: Manager> {
     inner: Mutex<PoolInner<M>>,
+    panic_policy: PanicPolicy,
+    on_idle: Option<EventCallback>,
+    local: Vec<Mutex<LocalSlot<M>>>,
+    // the count of waiters, `wait_until_idle` calls and the paused state. Connections are not cached in or taken
+    // from `local` when it's not 0 so everyone waiting on `inner` is served first.
+    parked: AtomicUsize,
+    // the count of connections cached in `local`. They are idle but not in `inner`.
+    local_idle: AtomicU8,
+    // `PoolInner::in_use` when the lock of `inner` is released. The checkouts from `local` read it as they don't
+    // take the lock. Only updated with the thread local cache.
+    inner_in_use: AtomicU8,
+    // the peak count of connections in use since the last `take_peak_in_use`.
+    peak_in_use: AtomicU8,
+    // true when some connection is handed out since the pool was last fully idle. See `Builder::on_idle`.
+    busy: AtomicBool,
+    // the count of times `inner` is locked.
+    #[cfg(test)]
+    lock_hits: AtomicUsize,
+}
+
+// the guard of `PoolInner`. It publishes the count of connections in use to `PoolLock` when the lock is released.
+struct InnerGuard<'a, M: Manager> {
+    guard: MutexGuard<'a, PoolInner<M>>,
+    pool_lock: &'a PoolLock<M>,
+}
+
+impl<M: Manager> Deref for InnerGuard<'_, M> {
+    type Target = PoolInner<M>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard
+    }
+}
+
+impl<M: Manager> DerefMut for InnerGuard<'_, M> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.guard
+    }
+}
+
+impl<M: Manager> Drop for InnerGuard<'_, M> {
+    fn drop(&mut self) {
+        if !self.pool_lock.local.is_empty() {
+            self.pool_lock
+                .inner_in_use
+                .store(self.guard.in_use(), Ordering::SeqCst);
+        }
+    }
 }
 
 impl<M: Manager> PoolLock<M> {
-    pub(crate) fn new(pool_size: usize) -> Self {
+    fn guard<'a>(&'a self, guard: MutexGuard<'a, PoolInner<M>>) -> InnerGuard<'a, M> {
+        #[cfg(test)]
+        self.lock_hits.fetch_add(1, Ordering::Relaxed);
+        InnerGuard {
+            guard,
+            pool_lock: self,
+        }
+    }
+
+    // recover the lock if it's poisoned by a panic of others while holding it.
+    // The methods returning or dropping connections use it so the counts of pool are still updated and the pool
+    // doesn't leak slots after a panic. (the counts are only mutated by non-panicking code while holding the lock)
+    fn lock_recover(&self) -> InnerGuard<'_, M> {
+        self.guard(lock_recover(&self.inner))
+    }
+
+    // lock with the `panic_policy` of pool.
+    fn lock_inner(&self) -> InnerGuard<'_, M> {
+        self.guard(lock_with(&self.inner, self.panic_policy))
+    }
+
+    // return None if the lock is held by others or poisoned.
+    fn try_lock_inner(&self) -> Option<InnerGuard<'_, M>> {
+        self.inner.try_lock().ok().map(|guard| self.guard(guard))
+    }
+
+    fn local_idle(&self) -> u8 {
+        self.local_idle.load(Ordering::SeqCst)
+    }
+
+    // the count of connections in use. The connections cached by threads are idle.
+    fn in_use(&self, inner: &PoolInner<M>) -> u8 {
+        inner.in_use().saturating_sub(self.local_idle())
+    }
+
+    // record a return to `inner` or `local` with the count of connections in use and idle after it. return true if
+    // the last connection in use is returned. It's reported once per busy to idle transition.
+    fn on_checkin(&self, in_use: u8, idle: u8) -> bool {
+        in_use == 0 && idle != 0 && self.busy.swap(false, Ordering::SeqCst)
+    }
+
+    fn call_on_idle(&self) {
+        if let Some(f) = self.on_idle.as_ref() {
+            f();
+        }
+    }
+
+    // record a checkout from `inner` or `local` with the count of connections in use after it.
+    fn on_checkout(&self, in_use: u8) {
+        let mut peak = self.peak_in_use.load(Ordering::SeqCst);
+        while in_use > peak {
+            match self.peak_in_use.compare_exchange(
+                peak,
+                in_use,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            ) {
+                Ok(_) => break,
+                Err(actual) => peak = actual,
+            }
+        }
+        self.busy.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn new(builder: &Builder) -> Self {
+        let pool_size = builder.max_size as usize;
+        let local = (0..builder.thread_local_cache)
+            .map(|_| {
+                Mutex::new(LocalSlot {
+                    conn: None,
+                    owner: None,
+                    stats: ConnectionStats::default(),
+                })
+            })
+            .collect();
         PoolLock {
+            panic_policy: builder.panic_policy,
+            on_idle: builder.on_idle.clone(),
+            local,
+            parked: AtomicUsize::new(0),
+            local_idle: AtomicU8::new(0),
+            inner_in_use: AtomicU8::new(0),
+            peak_in_use: AtomicU8::new(0),
+            busy: AtomicBool::new(false),
+            #[cfg(test)]
+            lock_hits: AtomicUsize::new(0),
             inner: Mutex::new(PoolInner {
                 spawned: 0,
                 pending: VecDeque::with_capacity(pool_size),
                 conn: VecDeque::with_capacity(pool_size),
                 waiters: WakerList::new(),
+                at_capacity: false,
+                empty: false,
+                paused: false,
+                max_pending: builder.max_pending,
+                mru_size: builder.mru_cache,
+                mru_len: 0,
+                idle_waiters: WakerList::new(),
+                shrink_waiters: WakerList::new(),
+                total_queries: 0,
+                total_bytes: 0,
+                #[cfg(feature = "metrics")]
+                queue_wait: Average::default(),
+                #[cfg(feature = "metrics")]
+                connect_time: Average::default(),
+                #[cfg(test)]
+                reap_scans: 0,
             }),
         }
     }
 
+    // update the `parked` count with the lock held.
+    fn sync_parked(&self, inner: &PoolInner<M>) {
+        if !self.local.is_empty() {
+            let parked = inner.waiters.len()
+                + inner.idle_waiters.len()
+                + inner.shrink_waiters.len()
+                + inner.paused as usize;
+            self.parked.store(parked, Ordering::SeqCst);
+        }
+    }
+
+    // the slot of current thread keyed by its id and the id.
+    fn local_slot(&self) -> Option<(&Mutex<LocalSlot<M>>, ThreadId)> {
+        if self.local.is_empty() {
+            return None;
+        }
+        let (id, hash) = THREAD_KEY.with(|key| *key);
+        let index = hash % self.local.len();
+        self.local.get(index).map(|slot| (slot, id))
+    }
+
+    // take the connection cached by current thread without locking `inner`. A connection cached by another thread
+    // sharing the slot is left for the shared path.(It's moved to `inner` when a waiter parks)
+    fn take_local(&self) -> Option<IdleConn<M>> {
+        let (slot, id) = self.local_slot()?;
+        if self.parked.load(Ordering::SeqCst) != 0 {
+            return None;
+        }
+        let mut slot = slot.try_lock().ok()?;
+        if slot.owner != Some(id) {
+            return None;
+        }
+        if let Some(not_before) = slot.conn.as_ref()?.not_before {
+            if Instant::now() < not_before {
+                return None;
+            }
+        }
+        let conn = slot.conn.take()?;
+        self.local_idle.fetch_sub(1, Ordering::SeqCst);
+        drop(slot);
+
+        let in_use = self
+            .inner_in_use
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.local_idle());
+        self.on_checkout(in_use);
+        Some(conn)
+    }
+
+    // cache a returned connection for current thread. return the connection and the stats not recorded yet if it
+    // should go to `inner` instead.
+    fn put_local(
+        &self,
+        conn: IdleConn<M>,
+        stats: ConnectionStats,
+    ) -> Result<(), (IdleConn<M>, ConnectionStats)> {
+        let (slot, id) = match self.local_slot() {
+            Some(slot) => slot,
+            None => return Err((conn, stats)),
+        };
+        if self.parked.load(Ordering::SeqCst) != 0 {
+            return Err((conn, stats));
+        }
+
+        match slot.try_lock() {
+            Ok(mut slot) if slot.conn.is_none() => {
+                slot.conn = Some(conn);
+                slot.owner = Some(id);
+                slot.stats.queries = slot.stats.queries.wrapping_add(stats.queries);
+                slot.stats.bytes = slot.stats.bytes.wrapping_add(stats.bytes);
+                self.local_idle.fetch_add(1, Ordering::SeqCst);
+            }
+            _ => return Err((conn, stats)),
+        }
+
+        // a waiter could park right before we cached the connection and miss it when flushing. take it back in
+        // this case.(The stats are left in slot)
+        if self.parked.load(Ordering::SeqCst) != 0 {
+            if let Ok(mut slot) = slot.try_lock() {
+                if let Some(conn) = slot.conn.take() {
+                    self.local_idle.fetch_sub(1, Ordering::SeqCst);
+                    return Err((conn, ConnectionStats::default()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // move all the connections cached by threads to `inner` and wake the waiters for them. The connections over
+    // `max_size` are dropped and returned.
+    pub(crate) fn flush_local(&self, max_size: u8) -> Vec<IdleConn<M>> {
+        let mut conns = Vec::new();
+        let mut stats = ConnectionStats::default();
+        for slot in self.local.iter() {
+            let mut slot = slot.lock().unwrap_or_else(PoisonError::into_inner);
+            if let Some(conn) = slot.conn.take() {
+                self.local_idle.fetch_sub(1, Ordering::SeqCst);
+                conns.push(conn);
+            }
+            let slot_stats = std::mem::take(&mut slot.stats);
+            stats.queries = stats.queries.wrapping_add(slot_stats.queries);
+            stats.bytes = stats.bytes.wrapping_add(slot_stats.bytes);
+        }
+
+        if conns.is_empty() && stats.queries == 0 && stats.bytes == 0 {
+            return conns;
+        }
+
+        let mut rejected = Vec::new();
+        let mut wakers = Vec::new();
+        let idle_waiters = {
+            let mut inner = self.lock_recover();
+            inner.total_queries = inner.total_queries.wrapping_add(stats.queries);
+            inner.total_bytes = inner.total_bytes.wrapping_add(stats.bytes);
+            for conn in conns.into_iter() {
+                if inner.total() > max_size {
+                    inner.decr_spawned_inner();
+                    rejected.push(conn);
+                } else {
+                    inner.push_returned(conn);
+                    inner.empty = false;
+                    if let Some(waker) = inner.wake_one_unpaused() {
+                        wakers.push(waker);
+                    }
+                }
+            }
+            inner.take_idle_waiters(self.local_idle())
+        };
+        for waker in wakers.into_iter().chain(idle_waiters) {
+            waker.wake();
+        }
+        rejected
+    }
+
     #[inline]
-    pub(crate) fn lock<'a>(&'a self, shared_pool: &'a Arc<SharedPool<M>>) -> PoolLockFuture<'a, M> {
+    // a waiter past the `deadline` is skipped when a connection is put back as it's timing out.
+    pub(crate) fn lock<'a>(
+        &'a self,
+        shared_pool: &'a Arc<SharedPool<M>>,
+        deadline: Option<Instant>,
+    ) -> PoolLockFuture<'a, M> {
+        let spins = match shared_pool.statics.wait_strategy {
+            WaitStrategy::Park => 0,
+            WaitStrategy::SpinThenPark { spins } => spins,
+        };
         PoolLockFuture {
             shared_pool,
             pool_lock: self,
             wait_key: None,
+            queue_position: None,
+            spawned_at: None,
+            deadline,
+            timed_out: false,
             acquired: false,
+            throttled: false,
+            spins,
+            wait: None,
+            #[cfg(feature = "metrics")]
+            start: Instant::now(),
         }
     }
 
-    // add pending directly to pool inner if we try to spawn new connections.
+    // add pending directly to pool inner if we try to spawn new connections to maintain min_idle.
     // and return the new pending count as option to notify the Pool to replenish connections
-    // we use closure here as it's not need to try spawn new connections every time we decr spawn count
+    // pass 0 as min_idle when it's not need to try spawn new connections
     // (like decr spawn count when a connection doesn't return to pool successfully)
-    pub(crate) fn decr_spawned<F>(&self, try_spawn: F) -> Option<u8>
-    where
-        F: FnOnce(u8) -> Option<u8>,
-    {
-        self.inner
-            .lock()
-            .map(|mut inner| {
-                inner.decr_spawned_inner();
+    pub(crate) fn decr_spawned(&self, min_idle: u8) -> Option<u8> {
+        let (pending_new, waker, idle_waiters) = {
+            let mut inner = self.lock_recover();
+            inner.decr_spawned_inner();
 
-                try_spawn(inner.total()).map(|pending_new| {
+            let (pending_new, waker) = match inner.needed_pending(min_idle) {
+                // a waiter parked at max_size would keep waiting for the freed slot. We wake it to spawn a new
+                // connection for itself.
+                0 => (None, inner.wake_one_unpaused()),
+                pending_new => {
                     inner.incr_pending_inner(pending_new);
-                    pending_new
-                })
-            })
-            .expect("Lock poisoned when decrementing spawned count")
+                    (Some(pending_new), None)
+                }
+            };
+            let idle_waiters = inner.take_idle_waiters(self.local_idle());
+            (pending_new, waker, idle_waiters)
+        };
+        waker.wake();
+        for waker in idle_waiters.into_iter() {
+            waker.wake();
+        }
+        pending_new
+    }
+
+    // add pending until the total count reach the target. return the new pending count as Some(u8).
+    pub(crate) fn incr_pending_to(&self, target: u8) -> Option<u8> {
+        let mut inner = self.lock_inner();
+        match inner.needed_pending(target) {
+            0 => None,
+            pending_new => {
+                inner.incr_pending_inner(pending_new);
+                Some(pending_new)
+            }
+        }
+    }
+
+    // add a pending if the total count is under max_size and the pending count is under max_pending.
+    // return true if a pending is added.
+    pub(crate) fn incr_pending_under(&self, max_size: u8) -> bool {
+        let mut inner = self.lock_inner();
+        if inner.total() < max_size && inner.cap_pending(1) != 0 {
+            inner.incr_pending_inner(1);
+            true
+        } else {
+            false
+        }
+    }
+
+    // add a pending for the waiters not covered by the idle and pending connections.
+    // It's used to continue the spawns capped by max_pending. return true if a pending is added.
+    pub(crate) fn incr_pending_for_waiters(&self, max_size: u8) -> bool {
+        let mut inner = self.lock_inner();
+        if inner.max_pending.is_none()
+            || inner.paused
+            || inner.waiters.len() <= inner.conn.len() + inner.pending.len()
+            || inner.total() >= max_size
+            || inner.cap_pending(1) == 0
+        {
+            return false;
+        }
+        inner.incr_pending_inner(1);
+        true
+    }
+
+    // add a pending if some waiters are still not covered by the idle and pending connections. It's used by the
+    // throttled spawns over `soft_max_size`. return true if a pending is added.
+    pub(crate) fn incr_pending_for_throttled(&self, max_size: u8) -> bool {
+        let mut inner = self.lock_inner();
+        if inner.paused
+            || inner.waiters.len() <= inner.conn.len() + inner.pending.len()
+            || inner.total() >= max_size
+            || inner.cap_pending(1) == 0
+        {
+            return false;
+        }
+        inner.incr_pending_inner(1);
+        true
     }
 
+    // it can be called when unwinding from a panic so we don't panic again on a poisoned lock.(See `lock_recover`)
+    // a waiter is waken so it can spawn a new connection in place of the dropped pending.
     pub(crate) fn decr_pending(&self, count: u8) {
-        self.inner.lock().unwrap().decr_pending_inner(count);
+        let mut inner = self.lock_recover();
+        inner.decr_pending_inner(count);
+        let waker = inner.wake_one_unpaused();
+        let idle_waiters = inner.take_idle_waiters(self.local_idle());
+        drop(inner);
+        waker.wake();
+        for waker in idle_waiters.into_iter() {
+            waker.wake();
+        }
     }
 
     pub(crate) fn drop_pendings<F>(&self, mut should_drop: F)
     where
         F: FnMut(&Pending) -> bool,
     {
-        let mut inner = self.inner.lock().unwrap();
+        let mut inner = self.lock_inner();
         let len = inner.pending.len();
 
         for index in 0..len {
@@ -125,73 +772,278 @@ impl<M: Manager> PoolLock<M> {
                 }
             }
         }
+
+        if inner.pending.len() != len {
+            let idle_waiters = inner.take_idle_waiters(self.local_idle());
+            drop(inner);
+            for waker in idle_waiters.into_iter() {
+                waker.wake();
+            }
+        }
     }
 
-    // return new pending count as Some(u8).
-    pub(crate) fn try_drop_conns<F>(&self, min_idle: u8, mut should_drop: F) -> Option<u8>
+    // return new pending count as Ok(Some(u8)) or Err(()) when the lock is contended and nothing is done.
+    // at most max_drop connections are dropped in one call and they are moved to `dropped`.
+    pub(crate) fn try_drop_conns<F>(
+        &self,
+        min_idle: u8,
+        max_drop: Option<u8>,
+        dropped: &mut Vec<IdleConn<M>>,
+        should_drop: F,
+    ) -> Result<Option<u8>, ()>
     where
         F: FnMut(&IdleConn<M>) -> bool,
     {
-        self.inner.try_lock().ok().and_then(|mut inner| {
-            let len = inner.conn.len();
-            for index in 0..len {
-                if let Some(conn) = inner.conn.get(index) {
-                    if should_drop(conn) {
-                        inner.conn.remove(index);
-                        inner.decr_spawned_inner();
-                    }
-                }
+        let mut inner = self.try_lock_inner().ok_or(())?;
+        Ok(inner.drop_conns(min_idle, max_drop, dropped, should_drop))
+    }
+
+    #[cfg(test)]
+    pub(crate) fn reap_scans(&self) -> usize {
+        self.lock_inner().reap_scans
+    }
+
+    // the same as `try_drop_conns` but block on the lock so the dropping always happens.
+    pub(crate) fn drop_conns_blocking<F>(
+        &self,
+        min_idle: u8,
+        max_drop: Option<u8>,
+        dropped: &mut Vec<IdleConn<M>>,
+        should_drop: F,
+    ) -> Option<u8>
+    where
+        F: FnMut(&IdleConn<M>) -> bool,
+    {
+        self.lock_inner()
+            .drop_conns(min_idle, max_drop, dropped, should_drop)
+    }
+
+    // take at most max idle connections next to be handed out for the background health check. Nothing is taken
+    // when the pool is contended.(the lock is held by others or some acquires are waiting)
+    pub(crate) fn take_idle_for_check(&self, max: u8) -> Vec<IdleConn<M>> {
+        let mut inner = match self.try_lock_inner() {
+            Some(inner) => inner,
+            None => return Vec::new(),
+        };
+        if inner.paused || !inner.waiters.is_empty() {
+            return Vec::new();
+        }
+
+        let now = Instant::now();
+        let mut conns = Vec::new();
+        let mut index = 0;
+        while conns.len() < max as usize {
+            let ready = match inner.conn.get(index) {
+                Some(conn) => conn.not_before.map(|t| t <= now).unwrap_or(true),
+                None => break,
+            };
+            // the connections in cooldown are skipped as they are not handed out yet.
+            if !ready {
+                index += 1;
+                continue;
+            }
+            if let Some(conn) = inner.remove_idle(index) {
+                conns.push(conn);
             }
+        }
+        inner.check_invariants();
+        conns
+    }
 
-            let total_now = inner.total();
-            if total_now < min_idle {
-                let pending_new = min_idle - total_now;
+    // drop idle connections until the total count is no more than max_size and return them.
+    pub(crate) fn drop_conns_over(&self, max_size: u8) -> Vec<IdleConn<M>> {
+        let mut inner = self.lock_inner();
+        let mut dropped = Vec::new();
+        while inner.total() > max_size {
+            match inner.pop_idle() {
+                Some(conn) => {
+                    inner.decr_spawned_inner();
+                    dropped.push(conn);
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
 
-                inner.incr_pending_inner(pending_new);
+    // return the connection if the pool is over max_size and the connection is dropped.
+    #[inline]
+    pub(crate) fn put_back(
+        &self,
+        conn: IdleConn<M>,
+        stats: ConnectionStats,
+        max_size: u8,
+    ) -> Option<IdleConn<M>> {
+        let (conn, stats) = match self.put_local(conn, stats) {
+            Ok(()) => {
+                let in_use = self
+                    .inner_in_use
+                    .load(Ordering::SeqCst)
+                    .saturating_sub(self.local_idle());
+                if self.on_checkin(in_use, self.local_idle()) {
+                    self.call_on_idle();
+                }
+                return None;
+            }
+            Err(conn) => conn,
+        };
 
-                Some(pending_new)
+        let (rejected, waker, idle_waiters, became_idle) = {
+            let mut inner = self.lock_recover();
+            inner.total_queries = inner.total_queries.wrapping_add(stats.queries);
+            inner.total_bytes = inner.total_bytes.wrapping_add(stats.bytes);
+            let (rejected, waker) = if inner.total() > max_size {
+                inner.decr_spawned_inner();
+                (Some(conn), None)
             } else {
+                inner.push_returned(conn);
+                inner.empty = false;
+                if inner.waiters.is_empty() {
+                    inner.at_capacity = false;
+                }
+                (None, inner.wake_one_unpaused())
+            };
+            let idle = inner.conn.len() as u8 + self.local_idle();
+            let became_idle = self.on_checkin(self.in_use(&inner), idle);
+            self.sync_parked(&inner);
+            inner.check_invariants();
+            let idle_waiters = inner.take_idle_waiters(self.local_idle());
+            (rejected, waker, idle_waiters, became_idle)
+        };
+        waker.wake();
+        for waker in idle_waiters.into_iter() {
+            waker.wake();
+        }
+        if became_idle {
+            self.call_on_idle();
+        }
+        rejected
+    }
+
+    // return the connection if the pool is already in full capacity.
+    pub(crate) fn put_back_incr_spawned(
+        &self,
+        conn: IdleConn<M>,
+        max_size: u8,
+    ) -> Option<IdleConn<M>> {
+        let (rejected, waker, idle_waiters) = {
+            let mut inner = self.lock_recover();
+            inner.decr_pending_inner(1);
+            let rejected = if inner.spawned < max_size {
+                inner.conn.push_back(conn);
+                inner.empty = false;
+                inner.spawned += 1;
                 None
-            }
-        })
+            } else {
+                Some(conn)
+            };
+            inner.check_invariants();
+            let idle_waiters = inner.take_idle_waiters(self.local_idle());
+            (rejected, inner.wake_one_unpaused(), idle_waiters)
+        };
+        waker.wake();
+        for waker in idle_waiters.into_iter() {
+            waker.wake();
+        }
+        rejected
     }
 
-    #[inline]
-    pub(crate) fn put_back(&self, conn: IdleConn<M>) {
-        self.inner
-            .lock()
-            .map(|mut inner| {
+    // push a connection established outside the pool. return the connection if the pool is already in full capacity.
+    pub(crate) fn put_back_seed(&self, conn: IdleConn<M>, max_size: u8) -> Option<IdleConn<M>> {
+        let (rejected, waker) = {
+            let mut inner = self.lock_recover();
+            let result = if inner.total() >= max_size {
+                (Some(conn), None)
+            } else {
                 inner.conn.push_back(conn);
-                inner.waiters.wake_one_weak()
-            })
-            .expect("Lock poisoned when putting back connection")
-            .wake();
-    }
-
-    pub(crate) fn put_back_incr_spawned(&self, conn: IdleConn<M>) {
-        self.inner
-            .lock()
-            .map(|mut inner| {
-                inner.decr_pending_inner(1);
-                if (inner.spawned as usize) < inner.conn.capacity() {
-                    inner.conn.push_back(conn);
-                    inner.spawned += 1;
-                }
-                inner.waiters.wake_one_weak()
-            })
-            .expect("Lock poisoned when putting back connection")
-            .wake();
+                inner.empty = false;
+                inner.spawned += 1;
+                (None, inner.wake_one_unpaused())
+            };
+            inner.check_invariants();
+            result
+        };
+        waker.wake();
+        rejected
+    }
+
+    // record the duration of a connect into the average of metrics. It's on the path of replacing a dropped
+    // connection so we don't panic on a poisoned lock.(See `lock_recover`)
+    #[cfg(feature = "metrics")]
+    pub(crate) fn record_connect_time(&self, connect_time: Duration) {
+        self.lock_recover().connect_time.record(connect_time);
+    }
+
+    // wake a waiter as an idle connection may be ready for it.
+    pub(crate) fn wake_one(&self) {
+        let waker = self.lock_recover().wake_one_unpaused();
+        waker.wake();
+    }
+
+    // return the peak count of connections in use and start a new window from the current count.
+    pub(crate) fn take_peak_in_use(&self) -> u8 {
+        let inner = self.lock_inner();
+        let in_use = self.in_use(&inner);
+        self.peak_in_use.swap(in_use, Ordering::SeqCst)
+    }
+
+    pub(crate) fn pause(&self) {
+        let mut inner = self.lock_inner();
+        inner.paused = true;
+        self.sync_parked(&inner);
+    }
+
+    // wake all the waiters parked while the pool is paused.
+    pub(crate) fn resume(&self) {
+        let wakers = {
+            let mut inner = self.lock_inner();
+            inner.paused = false;
+            self.sync_parked(&inner);
+            inner.waiters.drain_all()
+        };
+        for waker in wakers.into_iter() {
+            waker.wake();
+        }
+    }
+
+    pub(crate) fn is_paused(&self) -> bool {
+        self.lock_inner().paused
     }
 
     pub(crate) fn state(&self) -> State {
-        self.inner
-            .lock()
-            .map(|inner| State {
-                connections: inner.spawned,
-                idle_connections: inner.conn.len() as u8,
-                pending_connections: inner.pending.iter().cloned().collect(),
-            })
-            .unwrap()
+        self.lock_inner().state(self.local_idle())
+    }
+
+    pub(crate) fn stats(&self) -> PoolStats {
+        self.lock_inner().stats(self.local_idle())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn lock_hits(&self) -> usize {
+        self.lock_hits.load(Ordering::Relaxed)
+    }
+
+    // hold the lock for the duration like a saturated pool. `held` is called once the lock is taken.
+    #[cfg(test)]
+    pub(crate) fn hold<F: FnOnce()>(&self, duration: Duration, held: F) {
+        let _inner = self.inner.lock();
+        held();
+        std::thread::sleep(duration);
+    }
+
+    // poison the lock by panicking while holding it.
+    #[cfg(test)]
+    pub(crate) fn poison(&self) {
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _inner = self.inner.lock();
+            panic!("poison the pool lock");
+        }));
+    }
+
+    // the state of pool read from the lock even if it's poisoned.
+    #[cfg(test)]
+    pub(crate) fn state_recovered(&self) -> State {
+        self.lock_recover().state(self.local_idle())
     }
 }
 
@@ -201,7 +1053,22 @@ pub(crate) struct PoolLockFuture<'a, M: Manager> {
     shared_pool: &'a Arc<SharedPool<M>>,
     pool_lock: &'a PoolLock<M>,
     wait_key: Option<NonZeroUsize>,
+    // the count of waiters ahead of us when we are parked.
+    queue_position: Option<usize>,
+    // the time we spawned a new connection. The waiting is in the connecting phase after that.
+    spawned_at: Option<Instant>,
+    deadline: Option<Instant>,
+    // set by the caller timing us out without a `deadline`.(e.g. `queue_timeout`)
+    timed_out: bool,
     acquired: bool,
+    // true when we have scheduled a throttled spawn over `soft_max_size`.
+    throttled: bool,
+    // the retries left before parking with `WaitStrategy::SpinThenPark`.
+    spins: u32,
+    // the wait started when we are parked. Only tracked with `on_wait_start` or `on_wait_end`.
+    wait: Option<(WaitId, Instant)>,
+    #[cfg(feature = "metrics")]
+    start: Instant,
 }
 
 impl<M: Manager> Drop for PoolLockFuture<'_, M> {
@@ -210,14 +1077,25 @@ impl<M: Manager> Drop for PoolLockFuture<'_, M> {
         if let Some(wait_key) = self.wait_key {
             self.wake_cold(wait_key);
         }
+        if let Some(wait) = self.wait.take() {
+            let outcome = match self.deadline {
+                _ if self.timed_out => WaitOutcome::TimedOut,
+                Some(deadline) if Instant::now() >= deadline => WaitOutcome::TimedOut,
+                _ => WaitOutcome::Cancelled,
+            };
+            self.shared_pool.on_wait_end(wait, outcome);
+        }
     }
 }
 
 impl<M: Manager> PoolLockFuture<'_, M> {
     #[inline]
-    fn poll_idle_conn(&mut self, inner: &mut MutexGuard<'_, PoolInner<M>>) -> Poll<IdleConn<M>> {
-        match inner.conn.pop_front() {
+    fn poll_idle_conn(&mut self, inner: &mut PoolInner<M>) -> Poll<IdleConn<M>> {
+        match inner.pop_ready() {
             Some(conn) => {
+                self.pool_lock.on_checkout(self.pool_lock.in_use(inner));
+                #[cfg(feature = "metrics")]
+                inner.queue_wait.record(self.start.elapsed());
                 self.acquired = true;
                 Poll::Ready(conn)
             }
@@ -225,22 +1103,131 @@ impl<M: Manager> PoolLockFuture<'_, M> {
         }
     }
 
+    // return the time we spawned a new connection if we are in the connecting phase.
+    pub(crate) fn spawned_at(&self) -> Option<Instant> {
+        self.spawned_at
+    }
+
+    #[cfg(test)]
+    pub(crate) fn queue_position(&self) -> Option<usize> {
+        self.queue_position
+    }
+
+    // mark the wait as timed out so it's reported as `WaitOutcome::TimedOut` when we are dropped.
+    pub(crate) fn set_timed_out(&mut self) {
+        self.timed_out = true;
+    }
+
+    // reserve a pending connection for us if we have not hit the max pool size. The connection is spawned by
+    // `spawn_pending` after the lock is released.
     #[inline]
-    fn spawn_idle_conn(&self, inner: &mut MutexGuard<'_, PoolInner<M>>) {
-        let shared = self.shared_pool;
-        if inner.total() < shared.statics.max_size {
+    fn spawn_idle_conn(&self, inner: &mut PoolInner<M>, max_size: u8) -> Spawn {
+        if self.shared_pool.statics.fair_spawn {
+            // the count of waiters including us.
+            let waiting = inner.waiters.len() + if self.wait_key.is_none() { 1 } else { 0 };
+            // the pending connections are enough for all the waiters.
+            if inner.pending.len() >= waiting {
+                return Spawn::Skip;
+            }
+        }
+
+        if inner.total() < max_size {
+            let statics = &self.shared_pool.statics;
+            if let Some((soft_max_size, throttle_delay)) = statics.soft_max_size {
+                if inner.total() >= soft_max_size {
+                    return Spawn::Throttled(throttle_delay);
+                }
+            }
+
+            // some connections are in use and one of them could be returned to us soon.
+            if statics.prefer_idle_over_spawn && inner.spawned as usize > inner.conn.len() {
+                return Spawn::Throttled(statics.spawn_delay);
+            }
+
+            // we hit the max_pending. wait for the spawns of others and they will continue for us when finished.
+            if inner.cap_pending(1) == 0 {
+                return Spawn::Skip;
+            }
+
             inner.incr_pending_inner(1);
-            let shared_clone = shared.clone();
-            shared
-                .spawn(async move { shared_clone.add_idle_conn().await });
-//                .unwrap_or_else(|_| inner.decr_pending_inner(1));
+            Spawn::New
+        } else {
+            Spawn::AtCapacity
+        }
+    }
+
+    // spawn a connection after the throttle delay if we are still waiting by then.
+    // (the delay of `soft_max_size` or `spawn_delay`)
+    fn spawn_throttled(&mut self, throttle_delay: Duration) {
+        if self.throttled {
+            return;
         }
+        self.throttled = true;
+
+        let shared = self.shared_pool;
+        let shared_clone = shared.clone();
+        shared.spawn(async move {
+            delay_for(throttle_delay).await;
+            if shared_clone
+                .pool_lock
+                .incr_pending_for_throttled(shared_clone.max_size())
+            {
+                let _ = shared_clone.add_idle_conn().await;
+            }
+        });
+    }
+
+    // spawn the connection reserved by `spawn_idle_conn`.
+    fn spawn_pending(&mut self) {
+        let shared = self.shared_pool;
+        let shared_clone = shared.clone();
+        shared.spawn(async move {
+            let _ = shared_clone.add_idle_conn().await;
+            while shared_clone
+                .pool_lock
+                .incr_pending_for_waiters(shared_clone.max_size())
+            {
+                let _ = shared_clone.add_idle_conn().await;
+            }
+        });
+//            .unwrap_or_else(|_| inner.decr_pending_inner(1));
+        if self.spawned_at.is_none() {
+            self.spawned_at = Some(Instant::now());
+        }
+    }
+
+    // mark the pool at capacity and return a `State` for `on_spawn_limit` callback if it's the first park
+    // of this saturation.
+    #[cold]
+    fn at_capacity(&self, inner: &mut PoolInner<M>) -> Option<State> {
+        if inner.at_capacity {
+            return None;
+        }
+        inner.at_capacity = true;
+        self.shared_pool
+            .statics
+            .on_spawn_limit
+            .as_ref()
+            .map(|_| inner.state(self.pool_lock.local_idle()))
+    }
+
+    // mark the pool empty and return true for `on_pool_empty` callback if it's the first time this episode.
+    #[inline]
+    fn pool_empty(&self, inner: &mut PoolInner<M>, max_size: u8) -> bool {
+        if inner.empty || !inner.conn.is_empty() || inner.spawned < max_size {
+            return false;
+        }
+        inner.empty = true;
+        self.shared_pool.statics.on_pool_empty.is_some()
     }
 
     #[cold]
     fn wake_cold(&self, wait_key: NonZeroUsize) {
-        let mut inner = self.pool_lock.inner.lock().unwrap();
+        let mut inner = self.pool_lock.lock_inner();
+        check_wait_key(&inner, wait_key);
         let wait_key = unsafe { inner.waiters.remove(wait_key) };
+        self.pool_lock.sync_parked(&inner);
+        inner.check_invariants();
 
         if wait_key.is_none() && !self.acquired {
             // We were awoken but didn't acquire the lock. Wake up another task.
@@ -255,21 +1242,59 @@ impl<M: Manager> Future for PoolLockFuture<'_, M> {
     type Output = IdleConn<M>;
 
     fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
-        let mut inner = self.pool_lock.inner.lock().unwrap();
+        // try the connection cached by current thread first. It doesn't touch the lock of pool.
+        if self.wait_key.is_none() {
+            if let Some(conn) = self.pool_lock.take_local() {
+                self.acquired = true;
+                return Poll::Ready(conn);
+            }
+        }
+
+        // max_size is an atomic so we load it before taking the lock.
+        let max_size = self.shared_pool.max_size();
+
+        let mut inner = self.pool_lock.lock_inner();
 
         // poll a connection and use the result to mutate PoolLockFuture state as well as PoolInner state.
-        let poll = self.poll_idle_conn(&mut inner);
+        // a paused pool doesn't hand out connections. We park until `resume` wakes us.
+        let poll = if inner.paused {
+            Poll::Pending
+        } else {
+            self.poll_idle_conn(&mut inner)
+        };
+
+        // spin with `WaitStrategy::SpinThenPark`. We yield to the runtime and poll again without parking in case a
+        // connection is returned soon.
+        if poll.is_pending() && self.wait_key.is_none() && self.spins != 0 {
+            if !inner.paused && self.pool_lock.in_use(&inner) != 0 {
+                self.spins -= 1;
+                drop(inner);
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            // no connection could be returned to us so we stop spinning.
+            self.spins = 0;
+        }
+
+        let mut at_capacity = None;
+        let mut spawn = Spawn::Skip;
+        let mut parked = false;
+
+        let pool_empty = !inner.paused && self.pool_empty(&mut inner, max_size);
 
         // Either insert our waker if we don't have a wait key yet or overwrite the old waker entry if we already have a wait key.
         match self.wait_key {
             Some(wait_key) => {
+                check_wait_key(&inner, wait_key);
                 if poll.is_ready() {
                     // we got poll ready therefore remove self wait_key
                     unsafe { inner.waiters.remove(wait_key) };
                     self.wait_key = None;
                 } else {
                     // if we can't get a connection then we spawn new ones if we have not hit the max pool size.
-                    self.spawn_idle_conn(&mut inner);
+                    if !inner.paused {
+                        spawn = self.spawn_idle_conn(&mut inner, max_size);
+                    }
 
                     // if we are woken and have no key in waiters then we should not be in queue anymore.
                     let opt = unsafe { inner.waiters.get(wait_key) };
@@ -282,39 +1307,348 @@ impl<M: Manager> Future for PoolLockFuture<'_, M> {
             None => {
                 if poll.is_pending() {
                     // if we can't get a connection then we spawn new ones if we have not hit the max pool size.
-                    self.spawn_idle_conn(&mut inner);
+                    if !inner.paused {
+                        spawn = self.spawn_idle_conn(&mut inner, max_size);
+                        if let Spawn::AtCapacity = spawn {
+                            at_capacity = self.at_capacity(&mut inner);
+                        }
+                    }
 
                     let waker = cx.waker().clone();
-                    let wait_key = inner.waiters.insert(Some(waker));
+                    let (wait_key, position) = inner.waiters.insert(Some(waker), self.deadline);
                     self.wait_key = Some(wait_key);
+                    self.queue_position = Some(position);
+                    parked = true;
                 }
             }
         }
 
+        #[cfg(feature = "tracing")]
+        {
+            if poll.is_ready() {
+                tracing::debug!(
+                    queue_position = self.queue_position.unwrap_or(0) as u64,
+                    "pool.acquire"
+                );
+            }
+        }
+
+        self.pool_lock.sync_parked(&inner);
+        inner.check_invariants();
+
+        // spawn and call the callbacks after releasing the lock as they could try to lock the pool again.
+        drop(inner);
+        // the connections cached by threads are moved to pool for us.
+        if parked {
+            self.shared_pool.flush_local();
+            self.wait = self.shared_pool.on_wait_start();
+        }
+        if poll.is_ready() {
+            if let Some(wait) = self.wait.take() {
+                self.shared_pool.on_wait_end(wait, WaitOutcome::Acquired);
+            }
+        }
+        match spawn {
+            Spawn::New => self.spawn_pending(),
+            Spawn::Throttled(throttle_delay) => self.spawn_throttled(throttle_delay),
+            _ => {}
+        }
+        if let Some(state) = at_capacity {
+            if let Some(f) = self.shared_pool.statics.on_spawn_limit.as_ref() {
+                f(&state);
+            }
+        }
+        if pool_empty {
+            if let Some(f) = self.shared_pool.statics.on_pool_empty.as_ref() {
+                f();
+            }
+        }
+
         poll
     }
 }
 
-unsafe impl<M: Manager + Send> Send for PoolLock<M> {}
+// recover the mutex if it's poisoned. See `PoolLock::lock_recover` for when to use it.
+pub(crate) fn lock_recover<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// lock the mutex with the `panic_policy` of pool.
+pub(crate) fn lock_with<T>(mutex: &Mutex<T>, panic_policy: PanicPolicy) -> MutexGuard<'_, T> {
+    match panic_policy {
+        PanicPolicy::Propagate => mutex.lock().unwrap(),
+        PanicPolicy::Recover => lock_recover(mutex),
+    }
+}
+
+// panic if a waiter's key points to a node already removed from the waiter list. Only compiled with
+// `fairness-check` feature in debug builds.
+#[inline]
+#[allow(unused_variables)]
+fn check_wait_key<M: Manager>(inner: &PoolInner<M>, wait_key: NonZeroUsize) {
+    #[cfg(all(debug_assertions, feature = "fairness-check"))]
+    assert!(
+        inner.waiters.contains(wait_key),
+        "PoolLockFuture: wait_key points to a removed waiter"
+    );
+}
 
-unsafe impl<M: Manager + Send> Sync for PoolLock<M> {}
+// the result of `PoolLockFuture.spawn_idle_conn`.
+enum Spawn {
+    // no new connection is needed for us.
+    Skip,
+    // a pending connection is reserved and should be spawned after releasing the lock.
+    New,
+    // we are over `soft_max_size`(or prefer idle connections with `prefer_idle_over_spawn`) and a new connection is
+    // spawned after the delay if we are still waiting.
+    Throttled(Duration),
+    // we have hit the max pool size and can't spawn new connection.
+    AtCapacity,
+}
+
+// `IdleFuture` resolves when no connection is checked out from pool.
+pub(crate) struct IdleFuture<'a, M: Manager> {
+    pool_lock: &'a PoolLock<M>,
+    wait_key: Option<NonZeroUsize>,
+}
+
+impl<M: Manager> PoolLock<M> {
+    pub(crate) fn wait_until_idle(&self) -> IdleFuture<'_, M> {
+        IdleFuture {
+            pool_lock: self,
+            wait_key: None,
+        }
+    }
+}
+
+impl<M: Manager> Drop for IdleFuture<'_, M> {
+    fn drop(&mut self) {
+        if let Some(wait_key) = self.wait_key.take() {
+            let mut inner = self.pool_lock.lock_recover();
+            unsafe { inner.idle_waiters.remove(wait_key) };
+            self.pool_lock.sync_parked(&inner);
+        }
+    }
+}
+
+impl<M: Manager> Future for IdleFuture<'_, M> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.pool_lock.lock_inner();
+        if self.pool_lock.in_use(&inner) == 0 {
+            if let Some(wait_key) = self.wait_key.take() {
+                unsafe { inner.idle_waiters.remove(wait_key) };
+                self.pool_lock.sync_parked(&inner);
+            }
+            return Poll::Ready(());
+        }
+        match self.wait_key {
+            // the waker is taken when we are waken or it could be changed since the last poll.
+            Some(wait_key) => {
+                let opt = unsafe { inner.idle_waiters.get(wait_key) };
+                *opt = Some(cx.waker().clone());
+            }
+            None => {
+                let (wait_key, _) = inner.idle_waiters.insert(Some(cx.waker().clone()), None);
+                self.wait_key = Some(wait_key);
+            }
+        }
+        self.pool_lock.sync_parked(&inner);
+        drop(inner);
+
+        // the connections cached by threads are not in use. Move them to pool and we are waken if they are the last.
+        let _ = self.pool_lock.flush_local(u8::MAX);
+        Poll::Pending
+    }
+}
+
+// `ShrinkFuture` resolves when the count of connections(including pending ones) is no more than `max_size`.
+pub(crate) struct ShrinkFuture<'a, M: Manager> {
+    pool_lock: &'a PoolLock<M>,
+    max_size: u8,
+    wait_key: Option<NonZeroUsize>,
+}
+
+impl<M: Manager> PoolLock<M> {
+    // we are parked right away so the connections returned from now on are not cached by threads. The ones cached
+    // before are expected to be flushed by the caller.
+    pub(crate) fn wait_until_size(&self, max_size: u8) -> ShrinkFuture<'_, M> {
+        let mut inner = self.lock_inner();
+        let (wait_key, _) = inner.shrink_waiters.insert(None, None);
+        self.sync_parked(&inner);
+        ShrinkFuture {
+            pool_lock: self,
+            max_size,
+            wait_key: Some(wait_key),
+        }
+    }
+}
+
+impl<M: Manager> Drop for ShrinkFuture<'_, M> {
+    fn drop(&mut self) {
+        if let Some(wait_key) = self.wait_key.take() {
+            let mut inner = self.pool_lock.lock_recover();
+            unsafe { inner.shrink_waiters.remove(wait_key) };
+            self.pool_lock.sync_parked(&inner);
+        }
+    }
+}
+
+impl<M: Manager> Future for ShrinkFuture<'_, M> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut inner = self.pool_lock.lock_inner();
+        if inner.total() <= self.max_size {
+            if let Some(wait_key) = self.wait_key.take() {
+                unsafe { inner.shrink_waiters.remove(wait_key) };
+                self.pool_lock.sync_parked(&inner);
+            }
+            return Poll::Ready(());
+        }
+        match self.wait_key {
+            // the waker is taken when we are waken or it could be changed since the last poll.
+            Some(wait_key) => {
+                let opt = unsafe { inner.shrink_waiters.get(wait_key) };
+                *opt = Some(cx.waker().clone());
+            }
+            None => {
+                let (wait_key, _) = inner.shrink_waiters.insert(Some(cx.waker().clone()), None);
+                self.wait_key = Some(wait_key);
+            }
+        }
+        self.pool_lock.sync_parked(&inner);
+        Poll::Pending
+    }
+}
 
 pub struct State {
     pub connections: u8,
     pub idle_connections: u8,
     pub pending_connections: Vec<Pending>,
+    pub waiting: usize,
+    pub total_queries: u64,
+    pub total_bytes: u64,
+    /// The average time the acquires waited for a connection in queue.(Acquires served by the thread local cache
+    /// are not counted)
+    #[cfg(feature = "metrics")]
+    pub avg_queue_wait: Duration,
+    /// The average time of establishing new connections.(Including `connection_init_sql`)
+    #[cfg(feature = "metrics")]
+    pub avg_connect_time: Duration,
+}
+
+impl State {
+    /// The count of connections checked out from pool.
+    pub fn in_use(&self) -> u8 {
+        self.connections.saturating_sub(self.idle_connections)
+    }
+
+    /// The fraction of `max_size` currently checked out from pool. Clamped to `[0.0, 1.0]`.
+    pub fn utilization(&self, max_size: u8) -> f64 {
+        if max_size == 0 {
+            return 0.0;
+        }
+        let ratio = f64::from(self.in_use()) / f64::from(max_size);
+        ratio.max(0.0).min(1.0)
+    }
+
+    /// Format the counts of pool as gauges in the Prometheus text exposition format. The metrics are labeled with
+    /// `pool="<pool_name>"`.
+    ///
+    /// The output can be served as the body of a `/metrics` endpoint directly.
+    #[cfg(feature = "prometheus-text")]
+    pub fn as_prometheus(&self, pool_name: &str) -> String {
+        use std::fmt::Write;
+
+        let pool_name = pool_name
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('\n', "\\n");
+
+        let metrics = [
+            (
+                "pool_connections",
+                "The count of connections in pool.",
+                self.connections as usize,
+            ),
+            (
+                "pool_idle",
+                "The count of idle connections.",
+                self.idle_connections as usize,
+            ),
+            (
+                "pool_in_use",
+                "The count of connections checked out from pool.",
+                self.in_use() as usize,
+            ),
+            (
+                "pool_waiting",
+                "The count of acquires waiting for a connection.",
+                self.waiting,
+            ),
+            (
+                "pool_pending",
+                "The count of connections being established.",
+                self.pending_connections.len(),
+            ),
+        ];
+
+        let mut output = String::new();
+        for (name, help, value) in metrics.iter() {
+            let _ = writeln!(output, "# HELP {} {}", name, help);
+            let _ = writeln!(output, "# TYPE {} gauge", name);
+            let _ = writeln!(output, "{}{{pool=\"{}\"}} {}", name, pool_name, value);
+        }
+        output
+    }
 }
 
 impl fmt::Debug for State {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        fmt.debug_struct("State")
+        let mut debug = fmt.debug_struct("State");
+        debug
             .field("connections", &self.connections)
             .field("idle_connections", &self.idle_connections)
             .field("pending_connections", &self.pending_connections)
-            .finish()
+            .field("waiting", &self.waiting)
+            .field("total_queries", &self.total_queries)
+            .field("total_bytes", &self.total_bytes);
+        #[cfg(feature = "metrics")]
+        debug
+            .field("avg_queue_wait", &self.avg_queue_wait)
+            .field("avg_connect_time", &self.avg_connect_time);
+        debug.finish()
     }
 }
 
+/// A cheap snapshot of the counts of pool. Compare to `State` it doesn't contain the pending connections.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    pub connections: u8,
+    pub idle_connections: u8,
+    /// The count of connections checked out from pool.
+    pub in_use: u8,
+    /// The count of callers waiting in queue.
+    pub waiting: usize,
+    pub pending_connections: u8,
+}
+
+/// The detail of pool when a timeout happens while acquiring a connection.
+#[derive(Debug, Clone)]
+#[cfg_attr(not(feature = "capture-backtrace"), derive(Copy))]
+pub struct TimeoutInfo {
+    /// The duration waited before timeout.
+    pub waited: Duration,
+    /// The counts of pool at the time of timeout.
+    pub stats: PoolStats,
+    /// The unresolved backtrace of the acquire. It's captured when the acquire is first polled so it points at the
+    /// caller of `Pool.get()`. Call `resolve` on it to get the symbols.
+    #[cfg(feature = "capture-backtrace")]
+    pub backtrace: backtrace::Backtrace,
+}
+
 trait WakerOpt {
     fn wake(self);
 }
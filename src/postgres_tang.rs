@@ -11,7 +11,8 @@ use tokio_postgres::{
     Client, Config, Error, Socket, Statement,
 };
 
-use crate::manager::{Manager, ManagerFuture};
+use crate::builder::ConfigError;
+use crate::manager::{Manager, ManagerFuture, WithTls};
 use crate::PoolRef;
 
 pub struct PostgresManager<Tls>
@@ -69,6 +70,25 @@ where
     }
 }
 
+impl<Tls, T> WithTls<T> for PostgresManager<Tls>
+where
+    Tls: MakeTlsConnect<Socket>,
+    T: MakeTlsConnect<Socket> + Send + Sync + Clone + 'static,
+    T::Stream: Send,
+    T::TlsConnect: Send,
+    <T::TlsConnect as TlsConnect<Socket>>::Future: Send,
+{
+    type Output = PostgresManager<T>;
+
+    fn with_tls(self, tls: T) -> PostgresManager<T> {
+        PostgresManager {
+            config: self.config,
+            tls,
+            prepares: self.prepares,
+        }
+    }
+}
+
 impl<Tls> Manager for PostgresManager<Tls>
 where
     Tls: MakeTlsConnect<Socket> + Send + Sync + Clone + 'static,
@@ -120,6 +140,22 @@ where
     fn is_closed(&self, conn: &mut Self::Connection) -> bool {
         conn.0.is_closed()
     }
+
+    fn execute<'a>(
+        &'a self,
+        c: &'a mut Self::Connection,
+        sql: &'a str,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(c.0.batch_execute(sql).err_into())
+    }
+
+    fn supports_execute(&self) -> bool {
+        true
+    }
+
+    fn config_error(&self, error: ConfigError) -> Self::Error {
+        PostgresPoolError::Config(error)
+    }
 }
 
 impl<Tls> fmt::Debug for PostgresManager<Tls>
@@ -216,9 +252,8 @@ where
     <Tls::TlsConnect as TlsConnect<Socket>>::Future: Send,
 {
     fn prepare_statements(&mut self, statements: &[(&str, &str, &[Type])]) -> &mut Self {
-        let mut prepares = self
-            .pool
-            .manager
+        let manager = self.pool.manager();
+        let mut prepares = manager
             .prepares
             .write()
             .expect("Failed to lock/write prepared statements");
@@ -232,7 +267,7 @@ where
 
     fn clear_prepared_statements(&mut self) -> &mut Self {
         self.pool
-            .manager
+            .manager()
             .prepares
             .write()
             .expect("Failed to lock/write prepared statements")
@@ -257,6 +292,8 @@ impl From<(&str, &[Type])> for PreparedStatement {
 pub enum PostgresPoolError {
     Inner(Error),
     TimeOut,
+    /// An invalid setting of `Builder` found when building the pool.
+    Config(ConfigError),
 }
 
 impl fmt::Debug for PostgresPoolError {
@@ -267,6 +304,9 @@ impl fmt::Debug for PostgresPoolError {
                 .debug_struct("PostgresError")
                 .field("source", &"Connection Timeout")
                 .finish(),
+            PostgresPoolError::Config(e) => {
+                f.debug_struct("PostgresError").field("source", e).finish()
+            }
         }
     }
 }
@@ -4,6 +4,7 @@ use std::pin::Pin;
 use futures_util::TryFutureExt;
 use redis::{aio::MultiplexedConnection, Client, IntoConnectionInfo, RedisError};
 
+use crate::builder::ConfigError;
 use crate::manager::{Manager, ManagerFuture};
 use std::fmt;
 
@@ -39,6 +40,10 @@ impl Manager for RedisManager {
     fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
         false
     }
+
+    fn config_error(&self, error: ConfigError) -> Self::Error {
+        RedisPoolError::Config(error)
+    }
 }
 
 impl std::fmt::Debug for RedisManager {
@@ -50,6 +55,8 @@ impl std::fmt::Debug for RedisManager {
 pub enum RedisPoolError {
     Inner(RedisError),
     TimeOut,
+    /// An invalid setting of `Builder` found when building the pool.
+    Config(ConfigError),
 }
 
 impl fmt::Debug for RedisPoolError {
@@ -60,6 +67,7 @@ impl fmt::Debug for RedisPoolError {
                 .debug_struct("RedisError")
                 .field("source", &"Connection Timeout")
                 .finish(),
+            RedisPoolError::Config(e) => f.debug_struct("RedisError").field("source", e).finish(),
         }
     }
 }
@@ -0,0 +1,287 @@
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use tokio::time::delay_for;
+
+use crate::builder::ConfigError;
+use crate::manager::{Manager, ManagerFuture, SocketOptions};
+use crate::pool_inner::TimeoutInfo;
+
+/// An in memory `Manager` for testing the pool.
+///
+/// `TestManager` is cheap to clone and all the clones share the same state. Keep a clone after building the pool
+/// so that the behavior can be changed and the counters can be checked later.
+///
+/// # example:
+/// ```ignore
+/// use tang_rs::{Builder, TestManager};
+///
+/// #[tokio::test]
+/// async fn checkout_and_return() {
+///     let mgr = TestManager::new();
+///
+///     let pool = Builder::new()
+///         .always_check(true)
+///         .min_idle(1)
+///         .max_size(2)
+///         .build(mgr.clone())
+///         .await
+///         .unwrap();
+///
+///     let conn = pool.get().await.unwrap();
+///     assert_eq!(mgr.validations(), 1);
+///     drop(conn);
+///
+///     assert_eq!(pool.state().idle_connections, 1);
+///     assert_eq!(mgr.connects(), 1);
+///     assert_eq!(mgr.disconnects(), 0);
+/// }
+/// ```
+#[derive(Clone, Default)]
+pub struct TestManager {
+    inner: Arc<TestManagerInner>,
+}
+
+#[derive(Default)]
+struct TestManagerInner {
+    // fail the next n connects.
+    fail_connect: AtomicUsize,
+    connect_delay_millis: AtomicUsize,
+    invalid: AtomicBool,
+    connects: AtomicUsize,
+    connect_failures: AtomicUsize,
+    disconnects: Arc<AtomicUsize>,
+    validations: AtomicUsize,
+    executed: Mutex<Vec<String>>,
+    socket_options: Mutex<Option<SocketOptions>>,
+}
+
+impl TestManagerInner {
+    // return true and decrement the count if we should fail this connect.
+    fn take_fail_connect(&self) -> bool {
+        let mut n = self.fail_connect.load(Ordering::SeqCst);
+        while n != 0 {
+            match self
+                .fail_connect
+                .compare_exchange(n, n - 1, Ordering::SeqCst, Ordering::SeqCst)
+            {
+                Ok(_) => return true,
+                Err(actual) => n = actual,
+            }
+        }
+        false
+    }
+}
+
+impl TestManager {
+    /// Create a new `TestManager` that always connects successfully and has valid connections.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Make the next `times` connects fail with `TestError::Connect`.
+    pub fn fail_connect(&self, times: usize) -> &Self {
+        self.inner.fail_connect.store(times, Ordering::SeqCst);
+        self
+    }
+
+    /// Delay every connect with the `delay` duration.
+    pub fn connect_delay(&self, delay: Duration) -> &Self {
+        self.inner
+            .connect_delay_millis
+            .store(delay.as_millis() as usize, Ordering::SeqCst);
+        self
+    }
+
+    /// Set if the connections are valid. Invalid connections fail `Manager::is_valid` with `TestError::Invalid`.
+    pub fn set_valid(&self, valid: bool) -> &Self {
+        self.inner.invalid.store(!valid, Ordering::SeqCst);
+        self
+    }
+
+    /// The count of successful connects.
+    pub fn connects(&self) -> usize {
+        self.inner.connects.load(Ordering::SeqCst)
+    }
+
+    /// The count of failed connects.
+    pub fn connect_failures(&self) -> usize {
+        self.inner.connect_failures.load(Ordering::SeqCst)
+    }
+
+    /// The count of dropped connections.
+    pub fn disconnects(&self) -> usize {
+        self.inner.disconnects.load(Ordering::SeqCst)
+    }
+
+    /// The statements executed with `Manager::execute`. (in order)
+    pub fn executed(&self) -> Vec<String> {
+        self.inner.executed.lock().unwrap().clone()
+    }
+
+    /// The socket options received with `Manager::apply_socket_options`.
+    pub fn socket_options(&self) -> Option<SocketOptions> {
+        *self.inner.socket_options.lock().unwrap()
+    }
+
+    /// The count of `Manager::is_valid` calls.
+    pub fn validations(&self) -> usize {
+        self.inner.validations.load(Ordering::SeqCst)
+    }
+}
+
+impl Manager for TestManager {
+    type Connection = TestConnection;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        Box::pin(async move {
+            let inner = &self.inner;
+
+            let delay = inner.connect_delay_millis.load(Ordering::SeqCst);
+            if delay != 0 {
+                delay_for(Duration::from_millis(delay as u64)).await;
+            }
+
+            if inner.take_fail_connect() {
+                inner.connect_failures.fetch_add(1, Ordering::SeqCst);
+                return Err(TestError::Connect);
+            }
+
+            let id = inner.connects.fetch_add(1, Ordering::SeqCst);
+
+            Ok(TestConnection {
+                id,
+                closed: false,
+                disconnects: inner.disconnects.clone(),
+            })
+        })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.inner.validations.fetch_add(1, Ordering::SeqCst);
+            if self.inner.invalid.load(Ordering::SeqCst) {
+                Err(TestError::Invalid)
+            } else {
+                Ok(())
+            }
+        })
+    }
+
+    fn is_closed(&self, conn: &mut Self::Connection) -> bool {
+        conn.closed
+    }
+
+    fn execute<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+        sql: &'a str,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.inner.executed.lock().unwrap().push(sql.to_owned());
+            Ok(())
+        })
+    }
+
+    fn supports_execute(&self) -> bool {
+        true
+    }
+
+    fn apply_socket_options(&mut self, options: &SocketOptions) {
+        *self.inner.socket_options.lock().unwrap() = Some(*options);
+    }
+
+    fn timeout_error(&self, _elapsed: tokio::time::Elapsed, info: TimeoutInfo) -> Self::Error {
+        TestError::TimeOut(Some(info))
+    }
+
+    fn config_error(&self, error: ConfigError) -> Self::Error {
+        TestError::Config(error)
+    }
+}
+
+impl fmt::Debug for TestManager {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestManager")
+            .field("connects", &self.connects())
+            .field("connect_failures", &self.connect_failures())
+            .field("disconnects", &self.disconnects())
+            .field("validations", &self.validations())
+            .finish()
+    }
+}
+
+/// The connection of `TestManager`.
+pub struct TestConnection {
+    id: usize,
+    closed: bool,
+    disconnects: Arc<AtomicUsize>,
+}
+
+impl TestConnection {
+    /// The id of connection. Ids are given in the order of connects and start from 0.
+    pub fn id(&self) -> usize {
+        self.id
+    }
+
+    /// Mark this connection closed so it's treated as broken by the pool.
+    pub fn close(&mut self) {
+        self.closed = true;
+    }
+}
+
+impl Drop for TestConnection {
+    fn drop(&mut self) {
+        self.disconnects.fetch_add(1, Ordering::SeqCst);
+    }
+}
+
+impl fmt::Debug for TestConnection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("TestConnection")
+            .field("id", &self.id)
+            .field("closed", &self.closed)
+            .finish()
+    }
+}
+
+pub enum TestError {
+    Connect,
+    Invalid,
+    /// The detail is available when the timeout happens while acquiring a connection from pool.
+    TimeOut(Option<TimeoutInfo>),
+    Config(ConfigError),
+}
+
+impl fmt::Debug for TestError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TestError::Connect => f
+                .debug_struct("TestError")
+                .field("source", &"Connect Failed")
+                .finish(),
+            TestError::Invalid => f
+                .debug_struct("TestError")
+                .field("source", &"Invalid Connection")
+                .finish(),
+            TestError::TimeOut(info) => f
+                .debug_struct("TestError")
+                .field("source", &"Connection Timeout")
+                .field("info", info)
+                .finish(),
+            TestError::Config(e) => f.debug_struct("TestError").field("source", e).finish(),
+        }
+    }
+}
+
+impl From<tokio::time::Elapsed> for TestError {
+    fn from(_e: tokio::time::Elapsed) -> TestError {
+        TestError::TimeOut(None)
+    }
+}
@@ -0,0 +1,732 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use futures_util::future::poll_fn;
+
+use super::sleep;
+use crate::{Builder, ClosureManager, TestError, TestManager, WaitStrategy};
+
+#[tokio::test]
+async fn connection_timeout_bounds_acquire() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(100))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+
+    let start = Instant::now();
+    match pool.get().await {
+        Err(TestError::TimeOut(_)) => {}
+        r => panic!("expect a timeout. got: {:?}", r.map(|_| ())),
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(100));
+    assert!(elapsed < Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn queue_position_counts_waiters_ahead() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+
+    for _ in 0..3 {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let _ = pool.get().await;
+        });
+    }
+    sleep(20).await;
+    assert_eq!(pool.state().waiting, 3);
+
+    let mut fut = pool.0.pool_lock.lock(&pool.0, None);
+    poll_fn(|cx| {
+        assert!(Pin::new(&mut fut).poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+    assert!(fut.queue_position().unwrap() >= 3);
+}
+
+#[tokio::test]
+async fn waiter_at_max_size_spawns_for_slot_of_broken_connection() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .connection_timeout(Duration::from_secs(5))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let mut conn = pool.get().await.unwrap();
+
+    // the waiter parks at max_size before the broken connection frees its slot.
+    let mut waiter = Box::pin(pool.get());
+    poll_fn(|cx| {
+        assert!(waiter.as_mut().poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+
+    conn.close();
+    drop(conn);
+
+    let start = Instant::now();
+    drop(waiter.await.unwrap());
+    assert!(start.elapsed() < Duration::from_secs(1));
+    assert_eq!(mgr.connects(), 2);
+}
+
+#[cfg(feature = "blocking")]
+#[test]
+fn get_blocking_without_runtime() {
+    // the test thread never runs a runtime. The connections are spawned on the runtime of the crate.
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .build_uninitialized(mgr.clone())
+        .unwrap();
+
+    let conn = pool.get_blocking().unwrap();
+    assert_eq!(mgr.connects(), 1);
+    drop(conn);
+
+    drop(pool.get_blocking().unwrap());
+    assert_eq!(mgr.connects(), 1);
+
+    // the replenish of a broken connection is spawned when the PoolRef is dropped outside of a runtime.
+    let mut conn = pool.get_blocking().unwrap();
+    conn.close();
+    drop(conn);
+    drop(pool.get_blocking().unwrap());
+    assert_eq!(mgr.connects(), 2);
+}
+
+#[tokio::test]
+async fn timeout_error_carries_pool_counts() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .connection_timeout(Duration::from_millis(50))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conns = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+
+    // two waiters parked without a timeout.
+    for _ in 0..2 {
+        let pool = pool.clone();
+        tokio::spawn(async move {
+            let _ = pool.0.pool_lock.lock(&pool.0, None).await;
+        });
+    }
+    sleep(10).await;
+
+    let info = match pool.get().await {
+        Err(TestError::TimeOut(Some(info))) => info,
+        r => panic!("expect a timeout with info. got: {:?}", r.map(|_| ())),
+    };
+    assert!(info.waited >= Duration::from_millis(50));
+    assert_eq!(info.stats.connections, 2);
+    assert_eq!(info.stats.idle_connections, 0);
+    assert_eq!(info.stats.in_use, 2);
+    assert!(info.stats.waiting >= 2);
+}
+
+// the open and close time of a span.
+#[cfg(feature = "tracing")]
+type SpanTimes = (Instant, Option<Instant>);
+
+// record the open and close time of `pool.acquire` spans.
+#[cfg(feature = "tracing")]
+#[derive(Clone, Default)]
+struct SpanRecorder(std::sync::Arc<std::sync::Mutex<Vec<SpanTimes>>>);
+
+#[cfg(feature = "tracing")]
+impl tracing::Subscriber for SpanRecorder {
+    fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+        true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+        let mut spans = self.0.lock().unwrap();
+        if span.metadata().name() == "pool.acquire" {
+            spans.push((Instant::now(), None));
+        }
+        tracing::span::Id::from_u64(spans.len() as u64 + 1000)
+    }
+
+    fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+    fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+    fn event(&self, _event: &tracing::Event<'_>) {}
+
+    fn enter(&self, _span: &tracing::span::Id) {}
+
+    fn exit(&self, _span: &tracing::span::Id) {}
+
+    fn try_close(&self, id: tracing::span::Id) -> bool {
+        let mut spans = self.0.lock().unwrap();
+        let index = id.into_u64() as usize - 1001;
+        if let Some(span) = spans.get_mut(index) {
+            span.1 = Some(Instant::now());
+        }
+        true
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[tokio::test]
+async fn acquire_span_covers_hold_time() {
+    let recorder = SpanRecorder::default();
+    let dispatch = tracing::Dispatch::new(recorder.clone());
+    let pool = Builder::new().build(TestManager::new()).await.unwrap();
+
+    // the span is created when `get` is first polled so the subscriber is only set for the polls.
+    let mut get = Box::pin(pool.get());
+    let conn = poll_fn(|cx| tracing::dispatcher::with_default(&dispatch, || get.as_mut().poll(cx)))
+        .await
+        .unwrap();
+    sleep(50).await;
+    assert_eq!(recorder.0.lock().unwrap()[0].1, None);
+    drop(conn);
+
+    let (open, close) = recorder.0.lock().unwrap()[0];
+    let held = close.unwrap() - open;
+    assert!(held >= Duration::from_millis(50));
+    assert!(held < Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn pause_parks_acquires_until_resume() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    pool.pause();
+    let waiter = {
+        let pool = pool.clone();
+        tokio::spawn(async move { pool.get().await.map(|conn| conn.id()) })
+    };
+    sleep(20).await;
+
+    // the connection in use keeps working and the returned one is not handed to the paused waiter.
+    let id = conn.id();
+    drop(conn);
+    sleep(20).await;
+    assert_eq!(pool.state().idle_connections, 1);
+    assert_eq!(pool.state().waiting, 1);
+
+    pool.resume();
+    assert_eq!(waiter.await.unwrap().unwrap(), id);
+}
+
+#[tokio::test]
+async fn get_ephemeral_on_saturated_pool() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(20))
+        .max_ephemeral(1)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+    assert!(pool.get().await.is_err());
+
+    // the ephemeral connection works and is not counted against max_size.
+    let ephemeral = pool.get_ephemeral().await.unwrap();
+    assert_eq!(ephemeral.id(), 1);
+    assert_eq!(pool.state().connections, 1);
+    assert_eq!(mgr.connects(), 2);
+
+    // the cap of simultaneous ephemeral connections is hit.
+    assert!(pool.get_ephemeral().await.is_err());
+
+    drop(ephemeral);
+    assert_eq!(mgr.disconnects(), 1);
+    assert_eq!(pool.state().connections, 1);
+    assert!(pool.get_ephemeral().await.is_ok());
+}
+
+// a manager where the first `broken` connections fail the validation and the later ones are valid.
+fn failover_manager(broken: usize) -> ClosureManager<usize, TestError> {
+    let count = Arc::new(AtomicUsize::new(0));
+    ClosureManager::new(move || {
+        let n = count.fetch_add(1, Ordering::SeqCst);
+        async move { Ok(n) }
+    })
+    .with_validate(move |conn| {
+        let valid = *conn >= broken;
+        Box::pin(async move {
+            if valid {
+                Ok(())
+            } else {
+                Err(TestError::Invalid)
+            }
+        })
+    })
+}
+
+#[tokio::test]
+async fn acquire_retry_on_broken_reaches_fresh_connection() {
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(3)
+        .acquire_retry_on_broken(3)
+        .build(failover_manager(3))
+        .await
+        .unwrap();
+    assert!(*pool.get().await.unwrap() >= 3);
+
+    // the retries are used up by the broken idle connections.
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(3)
+        .acquire_retry_on_broken(1)
+        .build(failover_manager(3))
+        .await
+        .unwrap();
+    assert!(pool.get().await.is_err());
+}
+
+#[tokio::test]
+async fn queue_timeout_bounds_waiting_phase() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .queue_timeout(Some(Duration::from_millis(30)))
+        .connection_timeout(Duration::from_secs(1))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    // no connection can be spawned for us so we time out in the waiting phase.
+    let _conn = pool.get().await.unwrap();
+    let start = Instant::now();
+    assert!(pool.get().await.is_err());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(30));
+    assert!(elapsed < Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn queue_timeout_leaves_connecting_phase_to_connection_timeout() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(100));
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .queue_timeout(Some(Duration::from_millis(20)))
+        .connection_timeout(Duration::from_millis(500))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    // the connect spawned for us outlives queue_timeout and is bounded by connection_timeout.
+    let start = Instant::now();
+    assert!(pool.get().await.is_ok());
+    assert!(start.elapsed() >= Duration::from_millis(100));
+
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .queue_timeout(Some(Duration::from_millis(20)))
+        .connection_timeout(Duration::from_millis(50))
+        .build(mgr)
+        .await
+        .unwrap();
+
+    let start = Instant::now();
+    assert!(pool.get().await.is_err());
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(50));
+    assert!(elapsed < Duration::from_millis(100));
+}
+
+#[tokio::test]
+async fn connection_timeout_jitter_spreads_timeouts() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(100))
+        .connection_timeout_jitter(0.5)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+    let start = Instant::now();
+    let waiters = (0..4)
+        .map(|_| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                assert!(pool.get().await.is_err());
+                start.elapsed()
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut timeouts = Vec::new();
+    for waiter in waiters {
+        timeouts.push(waiter.await.unwrap());
+    }
+    timeouts.sort();
+
+    assert!(timeouts[0] >= Duration::from_millis(50));
+    assert!(timeouts[3] < Duration::from_millis(200));
+    assert!(timeouts[3] - timeouts[0] > Duration::from_millis(5));
+}
+
+#[tokio::test]
+async fn returned_connection_skips_expired_waiter() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    let conn = pool.get().await.unwrap();
+
+    // the waiter at the front is past its deadline but it's not polled again as if its timer has not fired yet.
+    let mut expired = pool
+        .0
+        .pool_lock
+        .lock(&pool.0, Some(Instant::now() + Duration::from_millis(10)));
+    poll_fn(|cx| {
+        assert!(Pin::new(&mut expired).poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+    sleep(20).await;
+
+    let live = {
+        let pool = pool.clone();
+        tokio::spawn(async move { pool.get().await.is_ok() })
+    };
+    sleep(10).await;
+    assert_eq!(pool.state().waiting, 2);
+
+    drop(conn);
+    let woken = tokio::time::timeout(Duration::from_millis(100), live).await;
+    assert!(woken.unwrap().unwrap());
+    drop(expired);
+}
+
+#[tokio::test]
+async fn pool_of_pool_ref_acquires_another_connection() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let count = Arc::strong_count(&pool.0);
+    let handle = conn.pool();
+    let other = handle.get().await.unwrap();
+    assert_ne!(conn.id(), other.id());
+    assert_eq!(pool.state().in_use(), 2);
+
+    // the handle is a plain clone of pool and doesn't outlive its drop.
+    drop(other);
+    drop(handle);
+    assert_eq!(Arc::strong_count(&pool.0), count);
+}
+
+// the invariants of pool are asserted after every poll, drop and put back with `fairness-check` feature.
+#[cfg(feature = "fairness-check")]
+#[tokio::test]
+async fn fairness_check_holds_under_stress() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(4)
+        .connection_timeout(Duration::from_millis(5))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let tasks = (0..32u64)
+        .map(|n| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                for _ in 0..20 {
+                    // some acquires are dropped before they resolve and some time out.
+                    if let Ok(Ok(conn)) =
+                        tokio::time::timeout(Duration::from_millis(n % 3), pool.get()).await
+                    {
+                        sleep(n % 2).await;
+                        drop(conn);
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+
+    let state = pool.state();
+    assert_eq!(state.waiting, 0);
+    assert_eq!(state.in_use(), 0);
+}
+
+#[tokio::test]
+async fn thread_local_cache_skips_pool_lock() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(2)
+        .thread_local_cache(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    // the first reaping started by the first use flushes the slots so the connection is cached by the next return.
+    drop(pool.get().await.unwrap());
+    sleep(10).await;
+    let id = pool.get().await.unwrap().id();
+
+    let lock_hits = pool.0.pool_lock.lock_hits();
+    for _ in 0..10 {
+        assert_eq!(pool.get().await.unwrap().id(), id);
+    }
+    assert_eq!(pool.0.pool_lock.lock_hits(), lock_hits);
+}
+
+#[tokio::test]
+async fn race_returns_connection_of_free_pool() {
+    let saturated = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    let free = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let held = saturated.get().await.unwrap();
+
+    let (index, conn) = crate::Pool::race(&[&saturated, &free]).await.unwrap();
+    assert_eq!(index, 1);
+    drop(conn);
+
+    // the losing acquire is cancelled and leaves no waiter behind.
+    assert_eq!(saturated.state().waiting, 0);
+    drop(held);
+    assert!(saturated.get().await.is_ok());
+}
+
+#[tokio::test]
+async fn invalidate_caches_bumps_generation_of_checkouts() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(conn.cache_generation(), Some(0));
+    assert!(!conn.cache_invalidated());
+    drop(conn);
+
+    assert_eq!(pool.invalidate_caches(), 1);
+    assert_eq!(pool.cache_generation(), 1);
+
+    // the same connection is checked out again and notices the invalidation only once.
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(conn.cache_generation(), Some(0));
+    assert!(conn.cache_invalidated());
+    assert_eq!(conn.cache_generation(), Some(1));
+    assert!(!conn.cache_invalidated());
+    drop(conn);
+
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(conn.cache_generation(), Some(1));
+    assert!(!conn.cache_invalidated());
+}
+
+#[tokio::test]
+async fn reuse_cooldown_skips_just_returned_connection() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .reuse_cooldown(Some(Duration::from_millis(100)))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let first = conn.connection_id();
+    drop(conn);
+
+    // the returned connection is in cooldown so the other idle one is handed out.
+    let conn = pool.get().await.unwrap();
+    assert_ne!(conn.connection_id(), first);
+    drop(conn);
+
+    sleep(150).await;
+
+    let conn1 = pool.get().await.unwrap();
+    let conn2 = pool.get().await.unwrap();
+    assert!([conn1.connection_id(), conn2.connection_id()].contains(&first));
+}
+
+#[tokio::test]
+async fn default_connection_timeout_bounds_hopeless_acquire() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+
+    // the connection is never returned so we wait for the default 10 seconds of connection_timeout.
+    let start = Instant::now();
+    match pool.get().await {
+        Err(TestError::TimeOut(_)) => {}
+        r => panic!("expect a timeout. got: {:?}", r.map(|_| ())),
+    }
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_secs(10));
+    assert!(elapsed < Duration::from_secs(11));
+}
+
+#[cfg(feature = "capture-backtrace")]
+#[tokio::test]
+async fn timeout_error_carries_backtrace() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(50))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+
+    let info = match pool.get().await {
+        Err(TestError::TimeOut(Some(info))) => info,
+        r => panic!("expect a timeout with info. got: {:?}", r.map(|_| ())),
+    };
+    assert!(!info.backtrace.frames().is_empty());
+}
+
+#[tokio::test]
+async fn create_timeout_budgets_cold_connect_only() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(80));
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(2)
+        .connection_timeout(Duration::from_millis(50))
+        .create_timeout(Some(Duration::from_millis(200)))
+        .build(mgr)
+        .await
+        .unwrap();
+
+    // the cold connect gets the longer budget of create_timeout.
+    let conn = pool.get().await.unwrap();
+
+    // a warm connect is bounded by the tighter connection_timeout.
+    let start = Instant::now();
+    assert!(pool.get().await.is_err());
+    assert!(start.elapsed() < Duration::from_millis(80));
+    drop(conn);
+}
+
+// poll an acquire once while the only connection is in use, then return the connection and finish the acquire. Return
+// the count of parked acquires.
+async fn parks_of_quick_return(wait_strategy: WaitStrategy) -> usize {
+    let parks = Arc::new(AtomicUsize::new(0));
+    let parks_clone = parks.clone();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .wait_strategy(wait_strategy)
+        .on_wait_start(move |_| {
+            parks_clone.fetch_add(1, Ordering::SeqCst);
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let held = pool.get().await.unwrap();
+    let mut get = Box::pin(pool.get());
+    poll_fn(|cx| {
+        assert!(get.as_mut().poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+
+    drop(held);
+    drop(get.await.unwrap());
+    parks.load(Ordering::SeqCst)
+}
+
+#[tokio::test]
+async fn spin_then_park_avoids_parking_on_quick_returns() {
+    assert_eq!(parks_of_quick_return(WaitStrategy::Park).await, 1);
+    assert_eq!(
+        parks_of_quick_return(WaitStrategy::SpinThenPark { spins: 4 }).await,
+        0
+    );
+}
+
+#[tokio::test]
+async fn connection_freed_at_deadline_wins_over_timeout() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(50))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let mut get = Box::pin(pool.get());
+    poll_fn(|cx| {
+        assert!(get.as_mut().poll(cx).is_pending());
+        Poll::Ready(())
+    })
+    .await;
+
+    // the waiter is not polled until its timer has fired and the connection is freed. The next poll sees both.
+    sleep(60).await;
+    drop(conn);
+    let result = poll_fn(|cx| Poll::Ready(get.as_mut().poll(cx))).await;
+    match result {
+        Poll::Ready(result) => assert!(result.is_ok()),
+        Poll::Pending => panic!("expect the waiter to be ready"),
+    }
+}
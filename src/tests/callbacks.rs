@@ -0,0 +1,244 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use super::sleep;
+use crate::{Builder, ConnectionId, TestManager, WaitOutcome};
+
+#[tokio::test]
+async fn on_spawn_limit_once_per_saturation() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_clone = count.clone();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .connection_timeout(Duration::from_millis(20))
+        .on_spawn_limit(move |_| {
+            count_clone.fetch_add(1, Ordering::SeqCst);
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conns = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+    assert!(pool.get().await.is_err());
+    assert!(pool.get().await.is_err());
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    drop(conns);
+    let _conns = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+    assert!(pool.get().await.is_err());
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+}
+
+#[tokio::test]
+async fn connect_and_disconnect_callbacks_share_ids() {
+    let connected = Arc::new(Mutex::new(Vec::<ConnectionId>::new()));
+    let disconnected = Arc::new(Mutex::new(Vec::<ConnectionId>::new()));
+    let connected_clone = connected.clone();
+    let disconnected_clone = disconnected.clone();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .on_connect_success(move |id| connected_clone.lock().unwrap().push(id))
+        .on_disconnect(move |id| disconnected_clone.lock().unwrap().push(id))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    assert_eq!(connected.lock().unwrap().len(), 2);
+
+    // the taken connection is reported with the id it was connected with and min_idle is replenished.
+    let mut conn = pool.get().await.unwrap();
+    let id = conn.connection_id().unwrap();
+    assert!(connected.lock().unwrap().contains(&id));
+    conn.take_conn();
+    drop(conn);
+    sleep(20).await;
+    assert_eq!(*disconnected.lock().unwrap(), [id]);
+
+    let connected = connected.lock().unwrap();
+    assert_eq!(connected.len(), 3);
+    assert_eq!(connected.iter().filter(|c| **c == id).count(), 1);
+}
+
+#[tokio::test]
+async fn on_get_slow_reports_waited_duration() {
+    let slow = Arc::new(Mutex::new(Vec::<Duration>::new()));
+    let slow_clone = slow.clone();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .on_get_slow(Duration::from_millis(30), move |waited| {
+            slow_clone.lock().unwrap().push(waited)
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    // a fast checkout doesn't trigger the callback.
+    drop(pool.get().await.unwrap());
+    assert!(slow.lock().unwrap().is_empty());
+
+    let conn = pool.get().await.unwrap();
+    let waiter = {
+        let pool = pool.clone();
+        tokio::spawn(async move { pool.get().await.is_ok() })
+    };
+    sleep(60).await;
+    drop(conn);
+    assert!(waiter.await.unwrap());
+
+    let slow = slow.lock().unwrap();
+    assert_eq!(slow.len(), 1);
+    assert!(slow[0] >= Duration::from_millis(60));
+    assert!(slow[0] < Duration::from_millis(500));
+}
+
+#[tokio::test]
+async fn on_pool_empty_once_per_episode() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_clone = count.clone();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .connection_timeout(Duration::from_millis(20))
+        .on_pool_empty(move || {
+            count_clone.fetch_add(1, Ordering::SeqCst);
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let first = pool.get().await.unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 0);
+
+    // checking out the last connection triggers it and the following failed acquires don't.
+    let second = pool.get().await.unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+    assert!(pool.get().await.is_err());
+    assert_eq!(count.load(Ordering::SeqCst), 1);
+
+    drop(second);
+    let _second = pool.get().await.unwrap();
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+    drop(first);
+}
+
+#[tokio::test]
+async fn on_stats_reports_counts_every_interval() {
+    let reports = Arc::new(Mutex::new(Vec::new()));
+    let reports_clone = reports.clone();
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(3)
+        .stats_interval(Duration::from_millis(20))
+        .on_stats(move |state| {
+            reports_clone
+                .lock()
+                .unwrap()
+                .push((state.connections, state.in_use()))
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+    reports.lock().unwrap().clear();
+    sleep(70).await;
+
+    let reports = reports.lock().unwrap();
+    assert!(reports.len() >= 2);
+    assert!(reports.len() <= 4);
+    assert!(reports.iter().all(|report| *report == (3, 1)));
+}
+
+#[tokio::test]
+async fn on_checkout_failure_sees_timeout() {
+    let failures = Arc::new(Mutex::new(Vec::new()));
+    let failures_clone = failures.clone();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(50))
+        .on_checkout_failure(move |e| failures_clone.lock().unwrap().push(format!("{:?}", e)))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    assert!(pool.get().await.is_err());
+    drop(conn);
+    assert!(pool.get().await.is_ok());
+
+    let failures = failures.lock().unwrap();
+    assert_eq!(failures.len(), 1);
+    assert!(failures[0].contains("Connection Timeout"));
+}
+
+#[tokio::test]
+async fn wait_hooks_fire_only_for_parked_checkout() {
+    let starts = Arc::new(Mutex::new(Vec::new()));
+    let ends = Arc::new(Mutex::new(Vec::new()));
+    let starts_clone = starts.clone();
+    let ends_clone = ends.clone();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .on_wait_start(move |id| starts_clone.lock().unwrap().push(id))
+        .on_wait_end(move |id, waited, outcome| {
+            ends_clone.lock().unwrap().push((id, waited, outcome))
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    // an immediate acquire doesn't fire the hooks.
+    let conn = pool.get().await.unwrap();
+    assert!(starts.lock().unwrap().is_empty());
+
+    let pool_clone = pool.clone();
+    let waiter = tokio::spawn(async move {
+        let _conn = pool_clone.get().await.unwrap();
+    });
+    sleep(30).await;
+    drop(conn);
+    waiter.await.unwrap();
+
+    let starts = starts.lock().unwrap();
+    let ends = ends.lock().unwrap();
+    assert_eq!(starts.len(), 1);
+    assert_eq!(ends.len(), 1);
+    let (id, waited, outcome) = ends[0];
+    assert_eq!(id, starts[0]);
+    assert!(waited >= Duration::from_millis(30));
+    assert_eq!(outcome, WaitOutcome::Acquired);
+}
+
+#[tokio::test]
+async fn on_idle_fires_once_per_busy_to_idle_transition() {
+    let idles = Arc::new(AtomicUsize::new(0));
+    let idles_clone = idles.clone();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .on_idle(move || {
+            idles_clone.fetch_add(1, Ordering::SeqCst);
+        })
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn1 = pool.get().await.unwrap();
+    let conn2 = pool.get().await.unwrap();
+    drop(conn1);
+    assert_eq!(idles.load(Ordering::SeqCst), 0);
+
+    // returning the last connection in use fires it exactly once.
+    drop(conn2);
+    assert_eq!(idles.load(Ordering::SeqCst), 1);
+    sleep(20).await;
+    assert_eq!(idles.load(Ordering::SeqCst), 1);
+
+    drop(pool.get().await.unwrap());
+    assert_eq!(idles.load(Ordering::SeqCst), 2);
+}
@@ -0,0 +1,685 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+use std::time::{Duration, Instant};
+
+use futures_util::future::poll_fn;
+use futures_util::stream::StreamExt;
+
+use super::sleep;
+use crate::{
+    Builder, ConnectionId, PanicPolicy, PoolEvent, ReapReason, ReaperMode, RecyclePolicy,
+    RecyclingMethod, TestManager,
+};
+
+#[tokio::test]
+async fn test_on_return_drops_invalid_connection() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .always_check(false)
+        .test_on_return(true)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    drop(pool.get().await.unwrap());
+    sleep(20).await;
+    assert_eq!(mgr.validations(), 1);
+    assert_eq!(pool.state().idle_connections, 1);
+    assert_eq!(mgr.disconnects(), 0);
+
+    mgr.set_valid(false);
+    drop(pool.get().await.unwrap());
+    sleep(20).await;
+    assert_eq!(mgr.validations(), 2);
+    assert_eq!(mgr.disconnects(), 1);
+
+    // the dropped connection is replaced to maintain min_idle.
+    assert_eq!(mgr.connects(), 2);
+    assert_eq!(pool.state().idle_connections, 1);
+}
+
+#[tokio::test]
+async fn max_idle_per_reap_shrinks_gradually() {
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(6)
+        .idle_timeout(Some(Duration::from_millis(10)))
+        .reaper_rate(Duration::from_millis(100))
+        .max_idle_per_reap(Some(2))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    for _ in 0..6 {
+        pool.connect_now().await.unwrap();
+    }
+    assert_eq!(pool.state().idle_connections, 6);
+
+    sleep(150).await;
+    assert_eq!(pool.state().idle_connections, 4);
+    sleep(100).await;
+    assert_eq!(pool.state().idle_connections, 2);
+    sleep(100).await;
+    assert_eq!(pool.state().idle_connections, 0);
+}
+
+#[tokio::test]
+async fn detach_replaces_connection() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(4)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap().detach().unwrap();
+    sleep(20).await;
+
+    let state = pool.state();
+    assert_eq!(state.connections, 2);
+    assert_eq!(state.idle_connections, 2);
+    assert_eq!(mgr.connects(), 3);
+
+    // the detached connection is not closed by the pool.
+    assert_eq!(mgr.disconnects(), 0);
+    drop(conn);
+    assert_eq!(mgr.disconnects(), 1);
+}
+
+#[tokio::test]
+async fn reaper_skips_scan_on_busy_pool() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .idle_timeout(Some(Duration::from_millis(1)))
+        .reaper_rate(Duration::from_secs(60))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let first = pool.get().await.unwrap();
+    let second = pool.get().await.unwrap();
+    for _ in 0..3 {
+        pool.0.reap_idle_conn().await.unwrap();
+    }
+    assert_eq!(pool.0.pool_lock.reap_scans(), 0);
+
+    // an idle connection makes the next pass scan again.
+    drop(first);
+    sleep(10).await;
+    let scans = pool.0.pool_lock.reap_scans();
+    pool.0.reap_idle_conn().await.unwrap();
+    assert_eq!(pool.0.pool_lock.reap_scans(), scans + 1);
+    drop(second);
+}
+
+#[tokio::test]
+async fn idle_timeout_and_max_lifetime_match_r2d2() {
+    // idle_timeout only closes the idle connections in excess of min_idle.
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(3)
+        .idle_timeout(Some(Duration::from_millis(10)))
+        .max_lifetime(None)
+        .reaper_rate(Duration::from_millis(50))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    pool.connect_now().await.unwrap();
+    pool.connect_now().await.unwrap();
+    assert_eq!(pool.state().idle_connections, 3);
+    sleep(120).await;
+    assert_eq!(pool.state().idle_connections, 1);
+
+    // max_lifetime closes any connection and min_idle is replenished with new ones.
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .idle_timeout(None)
+        .max_lifetime(Some(Duration::from_millis(10)))
+        .reaper_rate(Duration::from_millis(50))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    sleep(120).await;
+    assert!(mgr.disconnects() >= 1);
+    assert_eq!(pool.state().connections, 1);
+}
+
+#[tokio::test]
+async fn max_lifetime_replaces_connection_reused_past_it() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .idle_timeout(None)
+        .max_lifetime(Some(Duration::from_millis(100)))
+        .reaper_rate(Duration::from_secs(60))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    let mut events = pool.events();
+
+    let first = pool.get().await.unwrap().connection_id().unwrap();
+
+    // the connection is checked out and returned all the time so it's never idle long enough to be reaped.
+    for _ in 0..6 {
+        let conn = pool.get().await.unwrap();
+        sleep(30).await;
+        drop(conn);
+    }
+
+    let conn = pool.get().await.unwrap();
+    assert_ne!(conn.connection_id().unwrap(), first);
+    assert_eq!(mgr.connects(), 2);
+    assert_eq!(mgr.disconnects(), 1);
+
+    let mut reaped = None;
+    while let Some(event) = events.next().await {
+        if let PoolEvent::Reaped { id, reason } = event {
+            reaped = Some((id, reason));
+            break;
+        }
+    }
+    assert_eq!(reaped, Some((first, ReapReason::MaxLifetime)));
+}
+
+// poll a future once without waiting for it.
+async fn poll_once<F: Future + Unpin>(mut fut: F) -> Poll<F::Output> {
+    poll_fn(|cx| Poll::Ready(Pin::new(&mut fut).poll(cx))).await
+}
+
+#[tokio::test]
+async fn shrink_to_drains_a_large_pool() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(20)
+        .max_size(20)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    assert_eq!(pool.state().connections, 20);
+
+    let mut conns = Vec::new();
+    for _ in 0..8 {
+        conns.push(pool.get().await.unwrap());
+    }
+    let mut shrink = Box::pin(pool.shrink_to(5));
+    assert!(poll_once(shrink.as_mut()).await.is_pending());
+    assert_eq!(pool.state().connections, 8);
+    assert_eq!(pool.state().idle_connections, 0);
+    assert_eq!(mgr.disconnects(), 12);
+
+    // the returned connections are dropped until the pool is at the target size. We wait for the last excess one.
+    conns.truncate(6);
+    assert!(poll_once(shrink.as_mut()).await.is_pending());
+    assert_eq!(pool.state().connections, 6);
+    drop(conns);
+    shrink.await;
+    assert_eq!(pool.state().connections, 5);
+    assert_eq!(pool.state().idle_connections, 5);
+    assert_eq!(mgr.disconnects(), 15);
+
+    // min_idle is lowered to the target size and the pool doesn't grow again.
+    sleep(20).await;
+    assert_eq!(pool.state().connections, 5);
+    assert_eq!(pool.0.min_idle(), 5);
+    assert_eq!(pool.0.max_size(), 5);
+}
+
+#[tokio::test]
+async fn min_idle_age_keeps_just_returned_connection() {
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .idle_timeout(Some(Duration::from_millis(1)))
+        .min_idle_age(Duration::from_millis(100))
+        .reaper_rate(Duration::from_secs(60))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    drop(pool.get().await.unwrap());
+    sleep(10).await;
+    pool.0.reap_idle_conn().await.unwrap();
+    assert_eq!(pool.state().idle_connections, 1);
+
+    sleep(100).await;
+    pool.0.reap_idle_conn().await.unwrap();
+    assert_eq!(pool.state().idle_connections, 0);
+}
+
+#[tokio::test]
+async fn wait_until_idle_resolves_on_last_return() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let first = pool.get().await.unwrap();
+    let second = pool.get().await.unwrap();
+    let idle = Arc::new(AtomicBool::new(false));
+    let waiter = {
+        let pool = pool.clone();
+        let idle = idle.clone();
+        tokio::spawn(async move {
+            pool.wait_until_idle().await;
+            idle.store(true, Ordering::SeqCst);
+        })
+    };
+
+    sleep(10).await;
+    drop(first);
+    sleep(10).await;
+    assert!(!idle.load(Ordering::SeqCst));
+
+    drop(second);
+    sleep(10).await;
+    assert!(idle.load(Ordering::SeqCst));
+    waiter.await.unwrap();
+
+    // it resolves right away when no connection is in use.
+    pool.wait_until_idle().await;
+}
+
+#[tokio::test]
+async fn seeded_connections_are_available_right_away() {
+    let old = Builder::new()
+        .min_idle(3)
+        .max_size(3)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    let mut conns = Vec::new();
+    for _ in 0..3 {
+        conns.push(old.get().await.unwrap().take_conn().unwrap());
+    }
+    let ids = conns.iter().map(|conn| conn.id()).collect::<Vec<_>>();
+
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(2)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    assert_eq!(pool.seed(conns, true).await, 2);
+    assert_eq!(pool.state().idle_connections, 2);
+
+    // the seeded connections are checked out without connecting.
+    let first = pool.get().await.unwrap();
+    let second = pool.get().await.unwrap();
+    assert!(ids.contains(&first.id()));
+    assert!(ids.contains(&second.id()));
+    assert_eq!(mgr.connects(), 0);
+}
+
+#[tokio::test]
+async fn mru_cache_reuses_recent_connections() {
+    let pool = Builder::new()
+        .min_idle(4)
+        .max_size(4)
+        .mru_cache(2)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let mut ids = Vec::new();
+    for _ in 0..10 {
+        let first = pool.get().await.unwrap();
+        let second = pool.get().await.unwrap();
+        ids.push(first.id());
+        ids.push(second.id());
+    }
+    ids.sort();
+    ids.dedup();
+    assert_eq!(ids.len(), 2);
+}
+
+#[tokio::test]
+async fn put_back_accounts_on_poisoned_lock() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let mut broken = pool.get().await.unwrap();
+    pool.0.pool_lock.poison();
+
+    // the connection is put back and the broken one is dropped and replaced with the counts updated.
+    drop(conn);
+    broken.close();
+    drop(broken);
+    sleep(20).await;
+
+    let state = pool.0.pool_lock.state_recovered();
+    assert_eq!(state.connections, 2);
+    assert_eq!(state.idle_connections, 2);
+    assert!(state.pending_connections.is_empty());
+    assert_eq!(mgr.disconnects(), 1);
+}
+
+#[tokio::test]
+async fn recover_policy_keeps_pool_usable_after_poison() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .panic_policy(PanicPolicy::Recover)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    pool.0.pool_lock.poison();
+
+    for _ in 0..3 {
+        let conn = pool.get().await.unwrap();
+        assert_eq!(pool.state().in_use(), 1);
+        drop(conn);
+    }
+    assert_eq!(pool.state().idle_connections, 1);
+}
+
+#[tokio::test]
+#[should_panic(expected = "PoisonError")]
+async fn propagate_policy_panics_after_poison() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .panic_policy(PanicPolicy::Propagate)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    pool.0.pool_lock.poison();
+
+    let _ = pool.state();
+}
+
+#[tokio::test]
+async fn events_of_clear_and_refill() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .max_lifetime(Some(Duration::from_millis(30)))
+        .reaper_rate(Duration::from_secs(60))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    let mut events = pool.events();
+
+    let id = pool.get().await.unwrap().connection_id().unwrap();
+
+    // all the connections pass max_lifetime and the reaper replaces them.
+    sleep(40).await;
+    pool.0.reap_idle_conn().await.unwrap();
+
+    let mut received = Vec::new();
+    for _ in 0..5 {
+        received.push(events.next().await.unwrap());
+    }
+    assert_eq!(received[0], PoolEvent::Returned { id });
+
+    // the order among the reaped(and the spawned) ones follows the idle queue so only the membership is checked.
+    for n in 0..2 {
+        let reaped = PoolEvent::Reaped {
+            id: ConnectionId(n),
+            reason: ReapReason::MaxLifetime,
+        };
+        assert!(received[1..3].contains(&reaped));
+        let spawned = PoolEvent::Spawned {
+            id: ConnectionId(n + 2),
+        };
+        assert!(received[3..5].contains(&spawned));
+    }
+}
+
+#[tokio::test]
+async fn reaper_jitter_desynchronizes_pools() {
+    // each reaping drops one connection and we record the time of it.
+    let mut reaps = Vec::new();
+    let mut pools = Vec::new();
+    for _ in 0..2 {
+        let times = Arc::new(Mutex::new(Vec::new()));
+        let times_clone = times.clone();
+        let pool = Builder::new()
+            .min_idle(0)
+            .max_size(6)
+            .idle_timeout(Some(Duration::from_millis(1)))
+            .reaper_rate(Duration::from_millis(30))
+            .reaper_jitter(Duration::from_millis(20))
+            .max_idle_per_reap(Some(1))
+            .on_disconnect(move |_| times_clone.lock().unwrap().push(Instant::now()))
+            .build(TestManager::new())
+            .await
+            .unwrap();
+        for _ in 0..6 {
+            pool.connect_now().await.unwrap();
+        }
+        reaps.push(times);
+        pools.push(pool);
+    }
+    sleep(200).await;
+
+    let (first, second) = (reaps[0].lock().unwrap(), reaps[1].lock().unwrap());
+    assert!(first.len() >= 4);
+    assert!(second.len() >= 4);
+
+    // without the jitter the pools reap within a few milliseconds of each other on every tick.
+    let apart = first
+        .iter()
+        .zip(second.iter())
+        .filter(|(a, b)| {
+            let diff = if a > b { **a - **b } else { **b - **a };
+            diff > Duration::from_millis(3)
+        })
+        .count();
+    assert!(apart > 0);
+}
+
+#[tokio::test]
+async fn shutdown_force_closes_held_connection() {
+    // the checkouts are only tracked with max_checkout_duration. Only the count of them is kept otherwise.
+    shutdown_force_closes(None).await;
+    shutdown_force_closes(Some(Duration::from_secs(60))).await;
+}
+
+async fn shutdown_force_closes(max_checkout_duration: Option<Duration>) {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .max_checkout_duration(max_checkout_duration)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    assert_eq!(pool.0.checkouts.is_some(), max_checkout_duration.is_some());
+    let mut events = pool.events();
+
+    let conn = pool.get().await.unwrap();
+    let id = conn.connection_id().unwrap();
+
+    let start = Instant::now();
+    assert_eq!(pool.shutdown(Duration::from_millis(50)).await, 1);
+    assert!(start.elapsed() >= Duration::from_millis(50));
+    assert_eq!(pool.state().connections, 0);
+
+    // the forcibly closed connection is not put back when its PoolRef is dropped afterwards.
+    drop(conn);
+    assert_eq!(pool.state().connections, 0);
+
+    let mut received = Vec::new();
+    while let Ok(Some(event)) = tokio::time::timeout(Duration::from_millis(20), events.next()).await
+    {
+        received.push(event);
+    }
+    let forced = PoolEvent::Reaped {
+        id,
+        reason: ReapReason::Shutdown,
+    };
+    assert_eq!(received.iter().filter(|e| **e == forced).count(), 1);
+}
+
+#[tokio::test]
+async fn recycling_methods_with_failing_validation() {
+    // Fast puts back the broken connection as is.
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .always_check(false)
+        .connection_recycling_method(RecyclingMethod::Fast)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    mgr.set_valid(false);
+    drop(pool.get().await.unwrap());
+    sleep(20).await;
+    assert_eq!(mgr.validations(), 0);
+    assert_eq!(mgr.disconnects(), 0);
+    assert_eq!(mgr.connects(), 1);
+
+    // Verified drops the broken connection and replaces it.
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .always_check(false)
+        .connection_recycling_method(RecyclingMethod::Verified)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    mgr.set_valid(false);
+    drop(pool.get().await.unwrap());
+    sleep(20).await;
+    assert_eq!(mgr.validations(), 1);
+    assert_eq!(mgr.disconnects(), 1);
+    assert_eq!(mgr.connects(), 2);
+    assert_eq!(pool.state().idle_connections, 1);
+}
+
+#[tokio::test]
+async fn max_checkout_duration_reclaims_and_refills() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .max_checkout_duration(Some(Duration::from_millis(30)))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let held = pool.get().await.unwrap();
+    let id = held.connection_id();
+
+    // the wedged holder loses the slot and a replacement is spawned.
+    sleep(80).await;
+    let conn = pool.get().await.unwrap();
+    assert_ne!(conn.connection_id(), id);
+    assert_eq!(mgr.connects(), 2);
+    drop(conn);
+
+    // the reclaimed connection is not put back when the holder finally drops it.
+    drop(held);
+    let state = pool.state();
+    assert_eq!(state.connections, 1);
+    assert_eq!(state.idle_connections, 1);
+}
+
+#[tokio::test]
+async fn connection_of_panicking_task_is_reclaimed() {
+    for &policy in [RecyclePolicy::Broken, RecyclePolicy::Return].iter() {
+        let mgr = TestManager::new();
+        let pool = Builder::new()
+            .min_idle(1)
+            .max_size(1)
+            .return_conn_on_panic(policy)
+            .build(mgr.clone())
+            .await
+            .unwrap();
+
+        let conn = pool.get().await.unwrap();
+        let id = conn.connection_id();
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(move || {
+            let _conn = conn;
+            panic!("the task holding the connection panics");
+        }));
+        assert!(result.is_err());
+
+        // the slot is not leaked and with the broken policy the connection is replaced.
+        let conn = pool.get().await.unwrap();
+        match policy {
+            RecyclePolicy::Broken => {
+                assert_ne!(conn.connection_id(), id);
+                assert_eq!(mgr.disconnects(), 1);
+                assert_eq!(mgr.connects(), 2);
+            }
+            RecyclePolicy::Return => {
+                assert_eq!(conn.connection_id(), id);
+                assert_eq!(mgr.connects(), 1);
+            }
+        }
+    }
+}
+
+// reap a pool with 2 stale idle connections `reapings` times while another thread holds the lock of pool. Return the
+// count of connections left and the time of the reapings.
+async fn reap_contended_pool(reaper_mode: ReaperMode, reapings: usize) -> (u8, Duration) {
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(2)
+        .idle_timeout(Some(Duration::from_millis(1)))
+        .reaper_rate(Duration::from_secs(60))
+        .reaper_mode(reaper_mode)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    // let the first tick of the reaper pass before adding the connections.
+    sleep(10).await;
+    pool.connect_now().await.unwrap();
+    pool.connect_now().await.unwrap();
+    sleep(10).await;
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let pool_clone = pool.clone();
+    let holder = std::thread::spawn(move || {
+        pool_clone
+            .0
+            .pool_lock
+            .hold(Duration::from_millis(100), || tx.send(()).unwrap());
+    });
+    rx.recv().unwrap();
+
+    let start = Instant::now();
+    for _ in 0..reapings {
+        pool.0.reap_idle_conn().await.unwrap();
+    }
+    let elapsed = start.elapsed();
+
+    holder.join().unwrap();
+    (pool.state().connections, elapsed)
+}
+
+#[tokio::test]
+async fn forced_reaper_reaps_saturated_pool() {
+    // the best effort reaper never gets the lock.
+    let (connections, elapsed) = reap_contended_pool(ReaperMode::BestEffort, 5).await;
+    assert_eq!(connections, 2);
+    assert!(elapsed < Duration::from_millis(50));
+
+    // the forced reaper blocks on the lock after 2 skips and drops the stale connections.
+    let (connections, elapsed) = reap_contended_pool(ReaperMode::ForceAfter { skips: 2 }, 3).await;
+    assert_eq!(connections, 0);
+    assert!(elapsed >= Duration::from_millis(50));
+}
@@ -0,0 +1,54 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::task::{RawWaker, RawWakerVTable, Waker};
+
+use crate::util::linked_list::WakerList;
+
+// a waker counting the times it's waken.
+fn counting_waker(count: Arc<AtomicUsize>) -> Waker {
+    let ptr = Arc::into_raw(count) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(ptr, &VTABLE)) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+
+unsafe fn clone(ptr: *const ()) -> RawWaker {
+    let count = Arc::from_raw(ptr as *const AtomicUsize);
+    std::mem::forget(count.clone());
+    RawWaker::new(Arc::into_raw(count) as *const (), &VTABLE)
+}
+
+unsafe fn wake(ptr: *const ()) {
+    Arc::from_raw(ptr as *const AtomicUsize).fetch_add(1, Ordering::SeqCst);
+}
+
+unsafe fn wake_by_ref(ptr: *const ()) {
+    (*(ptr as *const AtomicUsize)).fetch_add(1, Ordering::SeqCst);
+}
+
+unsafe fn drop(ptr: *const ()) {
+    std::mem::drop(Arc::from_raw(ptr as *const AtomicUsize));
+}
+
+#[test]
+fn drain_all_takes_every_waker() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let mut list = WakerList::new();
+    let keys = (0..3)
+        .map(|_| list.insert(Some(counting_waker(count.clone())), None).0)
+        .collect::<Vec<_>>();
+
+    let wakers = list.drain_all();
+    assert_eq!(wakers.len(), 3);
+    assert!(list.iter_mut().all(|waker| waker.is_none()));
+    assert!(list.drain_all().is_empty());
+
+    // the wakers are waken after they are taken out of the list.
+    wakers.into_iter().for_each(Waker::wake);
+    assert_eq!(count.load(Ordering::SeqCst), 3);
+
+    for key in keys {
+        assert!(unsafe { list.remove(key) }.is_none());
+    }
+    assert!(list.is_empty());
+}
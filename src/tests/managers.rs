@@ -0,0 +1,630 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use super::sleep;
+use crate::manager::ManagerFuture;
+use crate::{
+    Builder, ClosureManager, ConfigError, Manager, MultiplexManager, SocketOptions, TestConnection,
+    TestError, TestManager, WithTls,
+};
+
+// a manager of composite connections. The two halves are established together and the pool treats them as one unit.
+struct CompositeManager(TestManager);
+
+impl Manager for CompositeManager {
+    type Connection = (TestConnection, TestConnection);
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        Box::pin(async move { Ok((self.0.connect().await?, self.0.connect().await?)) })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.0.is_valid(&mut conn.0).await?;
+            self.0.is_valid(&mut conn.1).await
+        })
+    }
+
+    fn is_closed(&self, conn: &mut Self::Connection) -> bool {
+        self.0.is_closed(&mut conn.0) || self.0.is_closed(&mut conn.1)
+    }
+
+    fn config_error(&self, error: ConfigError) -> Self::Error {
+        self.0.config_error(error)
+    }
+}
+
+#[tokio::test]
+async fn composite_connection_replaced_as_unit() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(CompositeManager(mgr.clone()))
+        .await
+        .unwrap();
+    assert_eq!(mgr.connects(), 2);
+
+    pool.get().await.unwrap().1.close();
+    sleep(20).await;
+
+    // both halves are dropped and a new unit is spawned. The unit is counted as one connection.
+    assert_eq!(mgr.disconnects(), 2);
+    assert_eq!(mgr.connects(), 4);
+    assert_eq!(pool.state().connections, 1);
+    assert_eq!(pool.state().idle_connections, 1);
+}
+
+#[tokio::test]
+async fn test_manager_behaviors_and_counters() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(20));
+
+    let start = Instant::now();
+    let pool = Builder::new()
+        .always_check(true)
+        .min_idle(1)
+        .max_size(1)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(20));
+    assert_eq!(mgr.connects(), 1);
+
+    let conn = pool.get().await.unwrap();
+    assert_eq!(conn.id(), 0);
+    assert_eq!(mgr.validations(), 1);
+    drop(conn);
+
+    mgr.fail_connect(1);
+    assert!(mgr.connect().await.is_err());
+    assert_eq!(mgr.connect_failures(), 1);
+
+    mgr.set_valid(false);
+    let mut conn = mgr.connect().await.unwrap();
+    assert!(mgr.is_valid(&mut conn).await.is_err());
+    assert_eq!(mgr.validations(), 2);
+
+    drop(conn);
+    assert_eq!(mgr.disconnects(), 1);
+}
+
+#[tokio::test]
+async fn connection_init_sql_runs_on_new_connections() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(3)
+        .connection_init_sql(vec!["SET a = 1".into(), "SET b = 2".into()])
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    assert_eq!(
+        mgr.executed(),
+        ["SET a = 1", "SET b = 2", "SET a = 1", "SET b = 2"]
+    );
+
+    // the statements are not run again on checkout.
+    drop(pool.get().await.unwrap());
+    assert_eq!(mgr.executed().len(), 4);
+}
+
+#[test]
+fn connection_init_sql_requires_execute() {
+    match Builder::new()
+        .connection_init_sql(vec!["SET a = 1".into()])
+        .build_uninitialized(CompositeManager(TestManager::new()))
+    {
+        Err(TestError::Config(ConfigError::ExecuteNotSupported)) => {}
+        r => panic!("expect a config error. got: {:?}", r.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn replace_manager_requires_execute() {
+    let pool = Builder::new()
+        .min_idle(0)
+        .connection_init_sql(vec!["SET a = 1".into()])
+        .build_unchecked(CompositeManager(TestManager::new()))
+        .await
+        .unwrap();
+
+    // the manager without execute is rejected and the old one is kept.
+    match pool.replace_manager(CompositeManager(TestManager::new())) {
+        Err(TestError::Config(ConfigError::ExecuteNotSupported)) => {}
+        r => panic!("expect a config error. got: {:?}", r),
+    }
+    assert!(!pool.0.manager_replaced.load(Ordering::Acquire));
+}
+
+// a manager capturing the TLS config forwarded by the pool builder on every connect.
+#[derive(Default)]
+struct TlsCaptureManager {
+    tls: Option<&'static str>,
+    connects: Arc<Mutex<Vec<Option<&'static str>>>>,
+}
+
+impl WithTls<&'static str> for TlsCaptureManager {
+    type Output = TlsCaptureManager;
+
+    fn with_tls(self, tls: &'static str) -> Self::Output {
+        TlsCaptureManager {
+            tls: Some(tls),
+            ..self
+        }
+    }
+}
+
+impl Manager for TlsCaptureManager {
+    type Connection = Option<&'static str>;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        self.connects.lock().unwrap().push(self.tls);
+        let tls = self.tls;
+        Box::pin(async move { Ok(tls) })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+}
+
+#[tokio::test]
+async fn build_with_tls_forwards_config() {
+    let mgr = TlsCaptureManager::default();
+    let connects = mgr.connects.clone();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .build_with_tls(mgr, "verify-full")
+        .await
+        .unwrap();
+
+    assert_eq!(*pool.get().await.unwrap(), Some("verify-full"));
+    assert_eq!(*connects.lock().unwrap(), [Some("verify-full"); 2]);
+}
+
+// a connection of some backend chosen at runtime.
+trait DbConn: Send {
+    fn backend(&self) -> &'static str;
+
+    fn ping(&mut self) -> ManagerFuture<'_, Result<(), TestError>>;
+}
+
+struct ConnA;
+
+impl DbConn for ConnA {
+    fn backend(&self) -> &'static str {
+        "a"
+    }
+
+    fn ping(&mut self) -> ManagerFuture<'_, Result<(), TestError>> {
+        Box::pin(async { Ok(()) })
+    }
+}
+
+// a manager of boxed trait object connections.
+struct DynManager;
+
+impl Manager for DynManager {
+    type Connection = Box<dyn DbConn>;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        Box::pin(async {
+            let conn: Box<dyn DbConn> = Box::new(ConnA);
+            Ok(conn)
+        })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        conn.ping()
+    }
+
+    fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+}
+
+#[tokio::test]
+async fn boxed_trait_object_connection() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(DynManager)
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    assert_eq!(conn.backend(), "a");
+    drop(conn);
+
+    let mut conn = pool.get().await.unwrap();
+    conn.ping().await.unwrap();
+    drop(conn);
+    assert_eq!(pool.state().idle_connections, 1);
+}
+
+#[tokio::test]
+async fn socket_options_are_passed_to_manager() {
+    let options = SocketOptions {
+        keepalive: Some(Duration::from_secs(30)),
+        nodelay: Some(true),
+        reuse_address: None,
+    };
+    let mgr = TestManager::new();
+    let _pool = Builder::new()
+        .socket_options(options)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+    assert_eq!(mgr.socket_options(), Some(options));
+
+    // the options are not applied when they are not set.
+    let mgr = TestManager::new();
+    let _pool = Builder::new().build(mgr.clone()).await.unwrap();
+    assert_eq!(mgr.socket_options(), None);
+}
+
+#[tokio::test]
+async fn closure_manager_builds_working_pool() {
+    // the connection is an id and a flag marking it closed.
+    let count = Arc::new(AtomicUsize::new(0));
+    let validated = Arc::new(AtomicUsize::new(0));
+    let validated_clone = validated.clone();
+    let mgr = ClosureManager::<(usize, bool), TestError>::new(move || {
+        let id = count.fetch_add(1, Ordering::SeqCst);
+        async move { Ok((id, false)) }
+    })
+    .with_validate(move |_conn| {
+        validated_clone.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async { Ok(()) })
+    })
+    .with_is_closed(|conn| conn.1);
+
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(mgr)
+        .await
+        .unwrap();
+
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(conn.0, 0);
+    assert_eq!(validated.load(Ordering::SeqCst), 1);
+
+    // the closed connection is dropped on return and replaced.
+    conn.1 = true;
+    drop(conn);
+    sleep(20).await;
+    assert_eq!(pool.get().await.unwrap().0, 1);
+}
+
+// a connection of a SQL backend. The queries fail after it's dead.
+struct SqlConn {
+    id: usize,
+    alive: bool,
+}
+
+// a SQL manager whose `is_valid` always passes so only the validation query can find the dead connections.
+#[derive(Default)]
+struct SqlManager {
+    count: AtomicUsize,
+    queries: Arc<Mutex<Vec<String>>>,
+}
+
+impl Manager for SqlManager {
+    type Connection = SqlConn;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        let id = self.count.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { Ok(SqlConn { id, alive: true }) })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+
+    fn execute<'a>(
+        &'a self,
+        conn: &'a mut Self::Connection,
+        sql: &'a str,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async move {
+            self.queries.lock().unwrap().push(sql.to_owned());
+            if conn.alive {
+                Ok(())
+            } else {
+                Err(TestError::Invalid)
+            }
+        })
+    }
+
+    fn supports_execute(&self) -> bool {
+        true
+    }
+}
+
+#[tokio::test]
+async fn validation_query_drops_dead_connection() {
+    let mgr = SqlManager::default();
+    let queries = mgr.queries.clone();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .validation_query("SELECT 1")
+        .build(mgr)
+        .await
+        .unwrap();
+
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(*queries.lock().unwrap(), ["SELECT 1"]);
+    assert_eq!(conn.id, 0);
+    conn.alive = false;
+    drop(conn);
+
+    // the dead connection fails the query and a fresh one is handed out instead.
+    let conn = pool.get().await.unwrap();
+    assert_eq!(conn.id, 1);
+    assert!(conn.alive);
+    assert_eq!(queries.lock().unwrap().len(), 3);
+}
+
+// a manager whose connections remember the last error so `quick_check` can spot them without I/O.
+#[derive(Default)]
+struct LastErrorManager {
+    count: AtomicUsize,
+}
+
+impl Manager for LastErrorManager {
+    type Connection = SqlConn;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        let id = self.count.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { Ok(SqlConn { id, alive: true }) })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+
+    fn quick_check(&self, conn: &Self::Connection) -> Result<(), Self::Error> {
+        if conn.alive {
+            Ok(())
+        } else {
+            Err(TestError::Invalid)
+        }
+    }
+}
+
+#[tokio::test]
+async fn quick_check_replaces_errored_connection() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .always_check(false)
+        .build(LastErrorManager::default())
+        .await
+        .unwrap();
+
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(conn.id, 0);
+    // a simulated error on the connection flips the quick_check to false.
+    conn.alive = false;
+    drop(conn);
+
+    let conn = pool.get().await.unwrap();
+    assert_eq!(conn.id, 1);
+    assert!(conn.alive);
+    drop(conn);
+    assert_eq!(pool.state().connections, 1);
+}
+
+// a manager of connections with 4 concurrent streams. The connection is an id of it.
+#[derive(Default)]
+struct StreamManager {
+    count: AtomicUsize,
+}
+
+impl Manager for StreamManager {
+    type Connection = usize;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        let id = self.count.fetch_add(1, Ordering::SeqCst);
+        Box::pin(async move { Ok(id) })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+}
+
+impl MultiplexManager for StreamManager {
+    fn max_concurrency_per_connection(&self) -> usize {
+        4
+    }
+}
+
+#[tokio::test]
+async fn multiplex_checkouts_share_one_connection() {
+    let pool = Builder::new()
+        .max_size(1)
+        .connection_timeout(Duration::from_millis(50))
+        .build_multiplex(StreamManager::default())
+        .unwrap();
+
+    let mut permits = Vec::new();
+    for _ in 0..4 {
+        permits.push(pool.get().await.unwrap());
+    }
+    assert!(permits.iter().all(|permit| **permit == 0));
+    assert_eq!(pool.state(), (1, 4));
+
+    // all the streams are taken and we can't open another connection.
+    assert!(pool.get().await.is_err());
+
+    permits.pop();
+    assert_eq!(*pool.get().await.unwrap(), 0);
+    assert_eq!(pool.state(), (1, 3));
+}
+
+#[tokio::test]
+async fn replace_manager_is_used_for_new_connections() {
+    let old = TestManager::new();
+    let new = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(2)
+        .build(old.clone())
+        .await
+        .unwrap();
+
+    let held = pool.get().await.unwrap();
+    pool.replace_manager(new.clone()).unwrap();
+
+    // the existing connection keeps working and the new one is established by the new manager.
+    let conn = pool.get().await.unwrap();
+    assert_eq!(old.connects(), 1);
+    assert_eq!(new.connects(), 1);
+    drop((held, conn));
+    assert_eq!(pool.state().connections, 2);
+}
+
+// a never replaced manager is borrowed, cloned and used for connects without touching the lock of the replaced one.
+#[tokio::test]
+async fn manager_access_skips_lock_until_replaced() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(2)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let held = pool.get().await.unwrap();
+    let conn = pool.get().await.unwrap();
+    drop((held, conn));
+    assert_eq!(pool.0.manager().connects(), 2);
+    assert_eq!(pool.0.manager_arc().connects(), 2);
+    assert_eq!(pool.0.manager_lock_uses.load(Ordering::Relaxed), 0);
+
+    pool.replace_manager(TestManager::new()).unwrap();
+    assert_eq!(pool.0.manager_lock_uses.load(Ordering::Relaxed), 1);
+    assert_eq!(pool.0.manager().connects(), 0);
+    assert_eq!(pool.0.manager_lock_uses.load(Ordering::Relaxed), 2);
+}
+
+#[tokio::test]
+async fn replace_manager_recovers_poisoned_lock() {
+    let new = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let shared = pool.0.clone();
+    let poisoned = std::thread::spawn(move || {
+        let _guard = shared.replaced_manager.write().unwrap();
+        panic!("poison the lock of replaced manager");
+    })
+    .join();
+    assert!(poisoned.is_err());
+    assert!(pool.0.replaced_manager.is_poisoned());
+
+    pool.replace_manager(new.clone()).unwrap();
+    drop(pool.get().await.unwrap());
+    assert_eq!(new.connects(), 1);
+}
+
+// there is no rolling replacement. Lowering `max_lifetime` rolls the existing connections over to the new manager.
+#[tokio::test]
+async fn replace_manager_rolls_over_by_max_lifetime() {
+    let old = TestManager::new();
+    let new = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .idle_timeout(None)
+        .reaper_rate(Duration::from_secs(60))
+        .build(old.clone())
+        .await
+        .unwrap();
+
+    let held = pool.get().await.unwrap();
+    pool.replace_manager(new.clone()).unwrap();
+    pool.set_max_lifetime(Some(Duration::from_millis(1)));
+    sleep(10).await;
+    drop(held);
+
+    drop(pool.get().await.unwrap());
+    assert_eq!(old.disconnects(), 1);
+    assert_eq!(new.connects(), 1);
+}
+
+#[tokio::test]
+async fn health_check_replaces_connection_killed_while_idle() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .always_check(false)
+        .validation_query("SELECT 1")
+        .health_check_interval(Some(Duration::from_millis(30)))
+        .build(SqlManager::default())
+        .await
+        .unwrap();
+
+    let mut conn = pool.get().await.unwrap();
+    assert_eq!(conn.id, 0);
+    conn.alive = false;
+    drop(conn);
+
+    // the background check finds the dead idle connection and replaces it within 2 intervals.
+    sleep(70).await;
+    assert_eq!(pool.0.manager().count.load(Ordering::SeqCst), 2);
+    assert_eq!(pool.state().idle_connections, 1);
+
+    let conn = pool.get().await.unwrap();
+    assert_eq!(conn.id, 1);
+    assert!(conn.alive);
+}
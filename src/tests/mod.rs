@@ -0,0 +1,17 @@
+use std::time::Duration;
+
+use tokio::time::delay_for;
+
+mod acquire;
+mod callbacks;
+mod lifecycle;
+mod linked_list;
+mod managers;
+mod pool_inner;
+mod spawn;
+mod state;
+
+// let the background tasks of pool make progress.
+async fn sleep(millis: u64) {
+    delay_for(Duration::from_millis(millis)).await;
+}
@@ -0,0 +1,18 @@
+use crate::pool_inner::{PoolLock, PoolLockFuture};
+use crate::util::linked_list::WakerList;
+use crate::{IdleConn, Pool, PoolRef, TestManager};
+
+fn is_send<T: Send>() {}
+fn is_send_sync<T: Send + Sync>() {}
+
+// `PoolLock` and `PoolLockFuture` are `Send` and `Sync` by their fields.(The only unsafe impls are on `WakerList`)
+// This fails the compile if a `!Send` or `!Sync` field slips in.
+#[test]
+fn send_sync() {
+    is_send::<IdleConn<TestManager>>();
+    is_send_sync::<WakerList>();
+    is_send_sync::<PoolLock<TestManager>>();
+    is_send::<PoolLockFuture<'_, TestManager>>();
+    is_send_sync::<Pool<TestManager>>();
+    is_send_sync::<PoolRef<'_, TestManager>>();
+}
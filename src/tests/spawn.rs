@@ -0,0 +1,729 @@
+use std::num::NonZeroU8;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures_util::future::{join, join_all};
+use tokio::time::delay_for;
+
+use super::sleep;
+#[cfg(feature = "cpu-count")]
+use crate::builder::max_size_of_cpus;
+use crate::{Builder, ClosureManager, ConfigError, TestError, TestManager};
+
+// a manager failing every other connect. The connections are the count of connects when they are established.
+fn flaky_manager() -> ClosureManager<usize, TestError> {
+    let count = Arc::new(AtomicUsize::new(0));
+    ClosureManager::new(move || {
+        let n = count.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if n % 2 == 1 {
+                Err(TestError::Connect)
+            } else {
+                Ok(n)
+            }
+        }
+    })
+}
+
+#[tokio::test]
+async fn build_partial_returns_pool_and_errors() {
+    let (pool, errors) = match Builder::new()
+        .min_idle(4)
+        .max_size(8)
+        .retry_connect(0)
+        .build_partial(flaky_manager())
+        .await
+    {
+        Ok(_) => panic!("expect the failed connects to be returned"),
+        Err(partial) => partial,
+    };
+
+    assert_eq!(errors.len(), 2);
+    assert!(pool.state().connections >= 2);
+
+    // the missing connections are replenished in background.
+    sleep(50).await;
+    assert!(pool.state().connections > 2);
+
+    let conn = pool.get().await.unwrap();
+    assert_eq!(*conn % 2, 0);
+}
+
+#[tokio::test]
+async fn lazy_min_idle_warms_up_on_first_use() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(4)
+        .lazy_min_idle(true)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    sleep(50).await;
+    assert_eq!(mgr.connects(), 0);
+    assert_eq!(pool.state().connections, 0);
+
+    // the first `get` could spawn one more connection for itself besides the warmup.
+    drop(pool.get().await.unwrap());
+    sleep(50).await;
+    let state = pool.state();
+    assert!(state.connections >= 3);
+    assert_eq!(state.idle_connections, state.connections);
+}
+
+// there is no runtime specific path. The pool grows on demand on every runtime.
+#[tokio::test]
+async fn grow_on_demand_past_min_idle() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(3)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conns = [
+        pool.get().await.unwrap(),
+        pool.get().await.unwrap(),
+        pool.get().await.unwrap(),
+    ];
+    assert_eq!(pool.state().connections, 3);
+}
+
+#[tokio::test]
+async fn retry_connect_gives_up_after_n_retries() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(2)
+        .retry_connect(2)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    mgr.fail_connect(3);
+    match pool.connect_now().await {
+        Err(TestError::Connect) => {}
+        r => panic!("expect a connect error. got: {:?}", r),
+    }
+    assert_eq!(mgr.connect_failures(), 3);
+    assert_eq!(pool.state().connections, 0);
+
+    mgr.fail_connect(2);
+    pool.connect_now().await.unwrap();
+    assert_eq!(mgr.connect_failures(), 5);
+    assert_eq!(pool.state().connections, 1);
+}
+
+#[tokio::test]
+async fn min_idle_recovers_from_connect_failures() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .retry_connect(0)
+        .reaper_rate(Duration::from_millis(50))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    // the broken connections can't be replaced while the connects fail.
+    mgr.fail_connect(1000);
+    let mut conns = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+    for conn in conns.iter_mut() {
+        conn.close();
+    }
+    drop(conns);
+    sleep(20).await;
+    assert_eq!(pool.state().connections, 0);
+
+    mgr.fail_connect(0);
+    sleep(120).await;
+    assert_eq!(pool.state().connections, 2);
+    assert_eq!(pool.state().idle_connections, 2);
+}
+
+#[tokio::test]
+async fn fair_spawn_burst_spawns_no_more_than_demand() {
+    // the connects finish one by one so the waiters are polled again while the others are still pending.
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_clone = count.clone();
+    let mgr = ClosureManager::new(move || {
+        let n = count_clone.fetch_add(1, Ordering::SeqCst);
+        async move {
+            delay_for(Duration::from_millis(10 * (n as u64 + 1))).await;
+            Ok::<_, TestError>(n)
+        }
+    });
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(10)
+        .fair_spawn(true)
+        .build(mgr)
+        .await
+        .unwrap();
+
+    let conns = join_all((0..4).map(|_| pool.get())).await;
+    assert!(conns.iter().all(Result::is_ok));
+    drop(conns);
+
+    sleep(100).await;
+    assert_eq!(count.load(Ordering::SeqCst), 4);
+    assert_eq!(pool.state().connections, 4);
+}
+
+#[tokio::test]
+async fn spawner_runs_background_connects() {
+    let spawns = Arc::new(AtomicUsize::new(0));
+    let spawns_clone = spawns.clone();
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(2)
+        .spawner(move |fut| {
+            spawns_clone.fetch_add(1, Ordering::SeqCst);
+            tokio::spawn(fut);
+        })
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    // the background tasks of pool are spawned with the spawner.
+    let background = spawns.load(Ordering::SeqCst);
+    assert!(background > 0);
+
+    // the connect for the second checkout is spawned with the spawner too.
+    let _conns = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+    assert!(spawns.load(Ordering::SeqCst) > background);
+    assert_eq!(mgr.connects(), 2);
+}
+
+#[cfg(all(feature = "fd-check", unix))]
+#[test]
+fn max_size_over_available_fds_is_rejected() {
+    use crate::builder::check_fd_limit;
+
+    // the descriptors already open are not available for the pool.
+    assert!(check_fd_limit(32, 64, 0).is_ok());
+    assert_eq!(
+        check_fd_limit(32, 64, 40),
+        Err(ConfigError::MaxSizeOverFdLimit { available: 24 })
+    );
+    assert_eq!(
+        check_fd_limit(32, 64, 100),
+        Err(ConfigError::MaxSizeOverFdLimit { available: 0 })
+    );
+
+    // the limit of the test process is left as is. It has room for a small pool.
+    assert!(Builder::new()
+        .max_size(8)
+        .build_uninitialized(TestManager::new())
+        .is_ok());
+}
+
+// a manager recording the peak count of connects in flight. Every connect takes 5 milliseconds.
+fn peak_connects_manager(peak: Arc<Mutex<usize>>) -> ClosureManager<usize, TestError> {
+    let in_flight = Arc::new(AtomicUsize::new(0));
+    ClosureManager::new(move || {
+        let in_flight = in_flight.clone();
+        let peak = peak.clone();
+        async move {
+            let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            {
+                let mut peak = peak.lock().unwrap();
+                *peak = now.max(*peak);
+            }
+            delay_for(Duration::from_millis(5)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(now)
+        }
+    })
+}
+
+#[tokio::test]
+async fn warmup_concurrency_bounds_connects_in_flight() {
+    let peak = Arc::new(Mutex::new(0));
+    let mgr = peak_connects_manager(peak.clone());
+
+    let pool = Builder::new()
+        .min_idle(50)
+        .max_size(50)
+        .warmup_concurrency(5)
+        .build(mgr)
+        .await
+        .unwrap();
+
+    assert_eq!(pool.state().connections, 50);
+    assert_eq!(*peak.lock().unwrap(), 5);
+}
+
+#[tokio::test]
+async fn spawn_on_acquire_only_spawns_nothing_in_background() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(3)
+        .spawn_on_acquire_only(true)
+        .reaper_rate(Duration::from_millis(10))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    sleep(30).await;
+    assert_eq!(mgr.connects(), 0);
+    assert_eq!(pool.state().connections, 0);
+
+    // only the connection for the checkout is spawned and no background task tops up min_idle.
+    drop(pool.get().await.unwrap());
+    sleep(30).await;
+    assert_eq!(mgr.connects(), 1);
+    assert_eq!(pool.state().connections, 1);
+}
+
+#[tokio::test]
+async fn build_unchecked_skips_validation() {
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(2)
+        .build_unchecked(TestManager::new())
+        .await;
+    assert!(pool.is_ok());
+}
+
+#[tokio::test]
+async fn build_validates_min_idle() {
+    match Builder::new()
+        .min_idle(3)
+        .max_size(2)
+        .build(TestManager::new())
+        .await
+    {
+        Err(TestError::Config(ConfigError::MinIdleOverMaxSize)) => {}
+        r => panic!("expect a config error. got: {:?}", r.map(|_| ())),
+    }
+}
+
+#[tokio::test]
+async fn max_pending_caps_burst_on_empty_pool() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(20));
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(8)
+        .max_pending(Some(2))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let burst = (0..8)
+        .map(|_| {
+            let pool = pool.clone();
+            tokio::spawn(async move {
+                let _conn = pool.get().await.unwrap();
+                delay_for(Duration::from_millis(100)).await;
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut peak = 0;
+    for _ in 0..20 {
+        sleep(5).await;
+        peak = peak.max(pool.state().pending_connections.len());
+    }
+    assert!(peak > 0);
+    assert!(peak <= 2);
+
+    join_all(burst).await;
+    assert_eq!(mgr.connects(), 8);
+}
+
+#[tokio::test]
+async fn adaptive_min_idle_follows_recent_peak() {
+    let pool = Builder::new()
+        .max_size(10)
+        .adaptive_min_idle(1, 8)
+        .reaper_rate(Duration::from_millis(20))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    assert_eq!(pool.0.min_idle(), 1);
+
+    // the sustained load of 8 connections keeps half of them warm.
+    let mut conns = Vec::new();
+    for _ in 0..8 {
+        conns.push(pool.get().await.unwrap());
+    }
+    sleep(60).await;
+    assert_eq!(pool.0.min_idle(), 4);
+    drop(conns);
+
+    // the peak leaves the window after the quiet time and min_idle goes back to the floor.
+    sleep(150).await;
+    assert_eq!(pool.0.min_idle(), 1);
+}
+
+#[tokio::test]
+async fn panicking_connect_does_not_wedge_pool() {
+    let count = Arc::new(AtomicUsize::new(0));
+    let count_clone = count.clone();
+    let mgr = ClosureManager::<usize, TestError>::new(move || {
+        let n = count_clone.fetch_add(1, Ordering::SeqCst);
+        async move {
+            if n == 0 {
+                panic!("malformed dsn");
+            }
+            Ok(n)
+        }
+    });
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .build(mgr)
+        .await
+        .unwrap();
+
+    // the waiter is woken to spawn a new connection in place of the pending dropped by the panic.
+    let conn = pool.get().await.unwrap();
+    assert_eq!(*conn, 1);
+    assert_eq!(count.load(Ordering::SeqCst), 2);
+    drop(conn);
+
+    let state = pool.state();
+    assert_eq!(state.connections, 1);
+    assert!(state.pending_connections.is_empty());
+}
+
+#[tokio::test]
+async fn connect_now_warms_up_to_max_size() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(3)
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    for n in 1..=2 {
+        pool.connect_now().await.unwrap();
+        assert_eq!(pool.state().idle_connections, n);
+    }
+
+    // it's a no-op once the pool is full.
+    for _ in 0..3 {
+        pool.connect_now().await.unwrap();
+    }
+    assert_eq!(pool.state().idle_connections, 3);
+    assert_eq!(mgr.connects(), 3);
+
+    // the connect error is returned.
+    let mgr = TestManager::new();
+    mgr.fail_connect(1000);
+    let pool = Builder::new()
+        .min_idle(0)
+        .retry_connect(0)
+        .build(mgr)
+        .await
+        .unwrap();
+    assert!(pool.connect_now().await.is_err());
+}
+
+// measure the wait for the pool lock while a slow spawner is spawning a connection for a `Pool.get()`. The spawn
+// happens after the lock is released so the wait doesn't include the spawn.
+#[test]
+fn lock_wait_excludes_slow_spawn() {
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    let slow = Arc::new(AtomicBool::new(false));
+    let slow_clone = slow.clone();
+    let pool = rt
+        .block_on(
+            Builder::new()
+                .min_idle(1)
+                .max_size(2)
+                .spawner(move |fut| {
+                    if slow_clone.load(Ordering::SeqCst) {
+                        std::thread::sleep(Duration::from_millis(200));
+                    }
+                    tokio::spawn(fut);
+                })
+                .build(TestManager::new()),
+        )
+        .unwrap();
+    let held = rt.block_on(pool.get()).unwrap();
+
+    slow.store(true, Ordering::SeqCst);
+    let pool_clone = pool.clone();
+    let getter = std::thread::spawn(move || rt.block_on(async { pool_clone.get().await.is_ok() }));
+    std::thread::sleep(Duration::from_millis(50));
+
+    let start = Instant::now();
+    let state = pool.state();
+    let wait = start.elapsed();
+    assert_eq!(state.pending_connections.len(), 1);
+    assert!(wait < Duration::from_millis(50), "lock wait: {:?}", wait);
+
+    assert!(getter.join().unwrap());
+    drop(held);
+}
+
+#[tokio::test]
+async fn soft_max_size_throttles_brief_burst() {
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(4)
+        .soft_max_size(2, Duration::from_millis(100))
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let conn1 = pool.get().await.unwrap();
+    let conn2 = pool.get().await.unwrap();
+    assert_eq!(pool.state().connections, 2);
+
+    // a brief burst over the soft max size is served by a returned connection.
+    let pool_clone = pool.clone();
+    let burst = tokio::spawn(async move {
+        let _conn = pool_clone.get().await.unwrap();
+    });
+    sleep(20).await;
+    drop(conn1);
+    burst.await.unwrap();
+    sleep(150).await;
+    assert_eq!(pool.state().connections, 2);
+
+    // a sustained demand climbs to max_size after the throttle delay.
+    let conn1 = pool.get().await.unwrap();
+    let conns = join_all(vec![pool.get(), pool.get()]).await;
+    assert!(conns.iter().all(Result::is_ok));
+    assert_eq!(pool.state().connections, 4);
+
+    drop((conn1, conn2, conns));
+}
+
+#[tokio::test]
+async fn build_rejects_zero_max_size() {
+    match Builder::new()
+        .min_idle(0)
+        .max_size(0)
+        .build(TestManager::new())
+        .await
+    {
+        Err(TestError::Config(ConfigError::ZeroMaxSize)) => {}
+        r => panic!("expect a config error. got: {:?}", r.map(|_| ())),
+    }
+    assert!(Builder::new()
+        .max_size(0)
+        .check(&TestManager::new())
+        .is_err());
+}
+
+#[test]
+#[should_panic(expected = "invalid settings of Builder: max_size must be larger than 0")]
+fn default_config_error_panics() {
+    // ClosureManager can't convert the error so the default `Manager::config_error` panics.
+    let _ = Builder::new()
+        .min_idle(0)
+        .max_size(0)
+        .build_uninitialized(flaky_manager());
+}
+
+#[tokio::test]
+async fn max_size_non_zero_sets_max_size() {
+    assert!(NonZeroU8::new(0).is_none());
+
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size_non_zero(NonZeroU8::new(3).unwrap())
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    assert_eq!(pool.0.max_size(), 3);
+}
+
+#[cfg(feature = "blocking")]
+#[tokio::test]
+async fn spawn_blocking_connect_keeps_runtime_responsive() {
+    // the connect blocks its thread like a driver without an async connect.
+    let mgr = ClosureManager::<usize, TestError>::new(|| async {
+        std::thread::sleep(Duration::from_millis(200));
+        Ok(0)
+    });
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .spawn_blocking_connect(true)
+        .build(mgr)
+        .await
+        .unwrap();
+
+    let pool_clone = pool.clone();
+    let get = tokio::spawn(async move {
+        let _conn = pool_clone.get().await.unwrap();
+    });
+
+    // the runtime keeps running the timers while the connect is blocking another thread.
+    let start = Instant::now();
+    for _ in 0..5 {
+        sleep(10).await;
+    }
+    assert!(start.elapsed() < Duration::from_millis(150));
+
+    get.await.unwrap();
+    assert_eq!(pool.state().connections, 1);
+}
+
+#[tokio::test]
+async fn build_warm_returns_partial_pool_on_warmup_timeout() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(40));
+
+    let start = Instant::now();
+    let pool = Builder::new()
+        .min_idle(4)
+        .max_size(4)
+        .warmup_concurrency(1)
+        .warmup_timeout(Duration::from_millis(100))
+        .build_warm(mgr.clone())
+        .await
+        .unwrap();
+    assert!(start.elapsed() < Duration::from_millis(150));
+
+    let idle = pool.state().idle_connections;
+    assert!(idle > 0 && idle < 4);
+
+    // with warmup_timeout_error the slow warmup is an error instead.
+    let result = Builder::new()
+        .min_idle(4)
+        .max_size(4)
+        .warmup_concurrency(1)
+        .warmup_timeout(Duration::from_millis(100))
+        .warmup_timeout_error(true)
+        .build_warm(mgr)
+        .await;
+    assert!(result.is_err());
+}
+
+#[tokio::test]
+async fn prefer_idle_over_spawn_waits_for_quick_return() {
+    let mgr = TestManager::new();
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(2)
+        .prefer_idle_over_spawn(true)
+        .spawn_delay(Duration::from_millis(100))
+        .build(mgr.clone())
+        .await
+        .unwrap();
+
+    let conn = pool.get().await.unwrap();
+    let pool_clone = pool.clone();
+    let waiter = tokio::spawn(async move {
+        let _conn = pool_clone.get().await.unwrap();
+    });
+
+    // the connection returned within the spawn_delay satisfies the waiter.
+    sleep(20).await;
+    drop(conn);
+    waiter.await.unwrap();
+    sleep(150).await;
+    assert_eq!(mgr.connects(), 1);
+    assert_eq!(pool.state().connections, 1);
+
+    // without a return a new connection is spawned after the delay.
+    let conn = pool.get().await.unwrap();
+    let start = Instant::now();
+    let conn2 = pool.get().await.unwrap();
+    assert!(start.elapsed() >= Duration::from_millis(100));
+    assert_eq!(mgr.connects(), 2);
+    drop((conn, conn2));
+}
+
+#[tokio::test]
+async fn concurrent_warmup_and_reaper_stay_within_min_idle() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(30));
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(6)
+        .reaper_rate(Duration::from_millis(5))
+        .build_uninitialized(mgr.clone())
+        .unwrap();
+
+    let pool_ref = &pool;
+    let reap = async move {
+        for _ in 0..10 {
+            pool_ref.0.reap_idle_conn().await.unwrap();
+            sleep(5).await;
+        }
+    };
+    let peak = async move {
+        let mut peak = 0;
+        for _ in 0..30 {
+            let state = pool_ref.state();
+            peak = peak.max(state.connections as usize + state.pending_connections.len());
+            sleep(2).await;
+        }
+        peak
+    };
+
+    let ((init, _), peak) = join(join(pool.init(), reap), peak).await;
+    init.unwrap();
+
+    assert_eq!(peak, 3);
+    assert_eq!(mgr.connects(), 3);
+    assert_eq!(pool.state().connections, 3);
+}
+
+#[tokio::test]
+async fn max_concurrent_connects_bounds_warmup_and_burst() {
+    let peak = Arc::new(Mutex::new(0));
+    let pool = Builder::new()
+        .min_idle(10)
+        .max_size(30)
+        .max_concurrent_connects(3)
+        .build_uninitialized(peak_connects_manager(peak.clone()))
+        .unwrap();
+
+    // the warmup and a burst of acquires spawn connections at the same time.
+    let burst = join_all((0..20).map(|_| pool.get()));
+    let (init, conns) = join(pool.init(), burst).await;
+    init.unwrap();
+    assert!(conns.iter().all(Result::is_ok));
+
+    assert_eq!(*peak.lock().unwrap(), 3);
+}
+
+#[test]
+fn lazy_background_tasks_start_on_first_get() {
+    // no runtime is running when the pool is built.
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(2)
+        .start_background_tasks(false)
+        .build_uninitialized(TestManager::new())
+        .unwrap();
+    assert!(!pool.0.started.load(Ordering::SeqCst));
+
+    let mut rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        drop(pool.get().await.unwrap());
+        assert!(pool.0.started.load(Ordering::SeqCst));
+
+        // the min_idle connections are replenished by the started tasks.
+        sleep(20).await;
+        assert_eq!(pool.state().idle_connections, 2);
+    });
+}
+
+#[cfg(feature = "cpu-count")]
+#[test]
+fn max_size_per_cpu_follows_core_count() {
+    assert_eq!(max_size_of_cpus(4, 8), 32);
+    assert_eq!(max_size_of_cpus(3, 1), 3);
+    // clamped to a sane size.
+    assert_eq!(max_size_of_cpus(0, 8), 1);
+    assert_eq!(max_size_of_cpus(16, 64), 255);
+
+    let builder = Builder::new().max_size_per_cpu(2);
+    assert_eq!(builder.max_size, max_size_of_cpus(2, num_cpus::get()));
+}
@@ -0,0 +1,215 @@
+use std::time::Duration;
+
+use super::sleep;
+use crate::manager::ManagerFuture;
+use crate::{Builder, ConnectionStats, Manager, TestError, TestManager};
+
+// a manager whose connections count the queries run on them. The count is reported and reset when returned.
+struct QueryCountManager;
+
+impl Manager for QueryCountManager {
+    type Connection = u64;
+    type Error = TestError;
+
+    fn connect(&self) -> ManagerFuture<Result<Self::Connection, Self::Error>> {
+        Box::pin(async { Ok(0) })
+    }
+
+    fn is_valid<'a>(
+        &'a self,
+        _conn: &'a mut Self::Connection,
+    ) -> ManagerFuture<'a, Result<(), Self::Error>> {
+        Box::pin(async { Ok(()) })
+    }
+
+    fn is_closed(&self, _conn: &mut Self::Connection) -> bool {
+        false
+    }
+
+    fn stats(&self, conn: &mut Self::Connection) -> ConnectionStats {
+        let queries = std::mem::replace(conn, 0);
+        ConnectionStats {
+            queries,
+            bytes: queries * 10,
+        }
+    }
+}
+
+#[tokio::test]
+async fn connection_stats_are_summed() {
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(QueryCountManager)
+        .await
+        .unwrap();
+
+    for queries in 1..4 {
+        let mut conn = pool.get().await.unwrap();
+        *conn += queries;
+    }
+
+    let state = pool.state();
+    assert_eq!(state.total_queries, 6);
+    assert_eq!(state.total_bytes, 60);
+}
+
+#[tokio::test]
+async fn stats_snapshot_matches_state() {
+    let pool = Builder::new()
+        .min_idle(3)
+        .max_size(4)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+
+    let _conn = pool.get().await.unwrap();
+
+    let stats = pool.stats_snapshot();
+    let state = pool.state();
+    assert_eq!(stats.connections, state.connections);
+    assert_eq!(stats.idle_connections, state.idle_connections);
+    assert_eq!(stats.in_use, state.in_use());
+    assert_eq!(stats.waiting, state.waiting);
+    assert_eq!(
+        stats.pending_connections as usize,
+        state.pending_connections.len()
+    );
+    assert_eq!(stats.in_use, 1);
+}
+
+#[tokio::test]
+async fn utilization_at_empty_half_and_full() {
+    let pool = Builder::new()
+        .min_idle(4)
+        .max_size(4)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    assert_eq!(pool.state().utilization(4), 0.0);
+
+    let half = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+    assert_eq!(pool.state().utilization(4), 0.5);
+
+    let full = [pool.get().await.unwrap(), pool.get().await.unwrap()];
+    assert_eq!(pool.state().utilization(4), 1.0);
+
+    // clamped when max_size is lowered below the connections in use.
+    assert_eq!(pool.state().utilization(2), 1.0);
+    assert_eq!(pool.state().utilization(0), 0.0);
+    drop((half, full));
+}
+
+#[cfg(feature = "metrics")]
+#[tokio::test]
+async fn phase_metrics_follow_their_cause() {
+    // slow connects without contention only move avg_connect_time.
+    let manager = TestManager::new();
+    manager.connect_delay(Duration::from_millis(50));
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(manager)
+        .await
+        .unwrap();
+    drop(pool.get().await.unwrap());
+
+    let state = pool.state();
+    assert!(state.avg_connect_time >= Duration::from_millis(50));
+    assert!(state.avg_queue_wait < Duration::from_millis(10));
+
+    // contention with fast connects only moves avg_queue_wait.
+    let pool = Builder::new()
+        .min_idle(1)
+        .max_size(1)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    let conn = pool.get().await.unwrap();
+    let pool_clone = pool.clone();
+    let waiter = tokio::spawn(async move {
+        drop(pool_clone.get().await.unwrap());
+    });
+    sleep(50).await;
+    drop(conn);
+    waiter.await.unwrap();
+
+    let state = pool.state();
+    assert!(state.avg_connect_time < Duration::from_millis(10));
+    assert!(state.avg_queue_wait >= Duration::from_millis(20));
+}
+
+#[tokio::test]
+async fn pending_snapshot_ages_grow() {
+    let mgr = TestManager::new();
+    mgr.connect_delay(Duration::from_millis(200));
+    let pool = Builder::new()
+        .min_idle(0)
+        .max_size(1)
+        .build(mgr)
+        .await
+        .unwrap();
+    assert!(pool.pending_snapshot().is_empty());
+
+    let pool_clone = pool.clone();
+    let get = tokio::spawn(async move {
+        let _conn = pool_clone.get().await.unwrap();
+    });
+    sleep(10).await;
+
+    let ages = pool.pending_snapshot();
+    assert_eq!(ages.len(), 1);
+    assert!(ages[0] < Duration::from_millis(100));
+
+    sleep(50).await;
+    let later = pool.pending_snapshot();
+    assert_eq!(later.len(), 1);
+    assert!(later[0] >= ages[0] + Duration::from_millis(50));
+
+    get.await.unwrap();
+    assert!(pool.pending_snapshot().is_empty());
+}
+
+#[cfg(feature = "prometheus-text")]
+#[tokio::test]
+async fn as_prometheus_is_valid_exposition() {
+    let pool = Builder::new()
+        .min_idle(2)
+        .max_size(3)
+        .build(TestManager::new())
+        .await
+        .unwrap();
+    let _conn = pool.get().await.unwrap();
+
+    let output = pool.state().as_prometheus("main \"db\"");
+
+    // every sample follows the HELP and TYPE lines of its metric.
+    let mut described = String::new();
+    let mut samples = Vec::new();
+    for line in output.lines() {
+        let words = line.splitn(4, ' ').collect::<Vec<_>>();
+        match (words[0], words[1]) {
+            ("#", "HELP") => described = words[2].to_owned(),
+            ("#", "TYPE") => assert_eq!(words[2..], [described.as_str(), "gauge"]),
+            _ => {
+                let split = line.rfind(' ').unwrap();
+                let (series, value) = (&line[..split], &line[split + 1..]);
+                assert!(value.parse::<f64>().is_ok());
+                assert!(series.starts_with(&format!("{}{{", described)));
+                assert!(series.ends_with('}'));
+                samples.push(line);
+            }
+        }
+    }
+
+    assert_eq!(
+        samples,
+        [
+            "pool_connections{pool=\"main \\\"db\\\"\"} 2",
+            "pool_idle{pool=\"main \\\"db\\\"\"} 1",
+            "pool_in_use{pool=\"main \\\"db\\\"\"} 1",
+            "pool_waiting{pool=\"main \\\"db\\\"\"} 0",
+            "pool_pending{pool=\"main \\\"db\\\"\"} 0",
+        ]
+    );
+}
@@ -0,0 +1,40 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+// `u64::MAX` nanoseconds(about 584 years) is reserved for `None`.
+const NONE: u64 = u64::MAX;
+
+// an `Option<Duration>` that can be loaded and stored without locking. The duration is stored as nanoseconds and
+// saturates at `NONE - 1`.
+pub(crate) struct AtomicDuration(AtomicU64);
+
+impl AtomicDuration {
+    pub(crate) fn new(duration: Option<Duration>) -> Self {
+        AtomicDuration(AtomicU64::new(to_nanos(duration)))
+    }
+
+    pub(crate) fn load(&self) -> Option<Duration> {
+        match self.0.load(Ordering::Relaxed) {
+            NONE => None,
+            nanos => Some(Duration::from_nanos(nanos)),
+        }
+    }
+
+    pub(crate) fn store(&self, duration: Option<Duration>) {
+        self.0.store(to_nanos(duration), Ordering::Relaxed);
+    }
+}
+
+fn to_nanos(duration: Option<Duration>) -> u64 {
+    match duration {
+        Some(duration) => {
+            let nanos = duration.as_nanos();
+            if nanos >= NONE as u128 {
+                NONE - 1
+            } else {
+                nanos as u64
+            }
+        }
+        None => NONE,
+    }
+}
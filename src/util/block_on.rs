@@ -0,0 +1,66 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Once};
+use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+use std::thread::{self, Thread};
+
+// A minimal executor that drives a future to complete on the current thread.
+// The thread is parked when the future is pending and the waker unparks it.
+pub(crate) fn block_on<F: Future>(mut fut: F) -> F::Output {
+    let waker = thread_waker(thread::current());
+    let mut cx = Context::from_waker(&waker);
+
+    // Safety: fut is shadowed and can't be moved after pinned.
+    let mut fut = unsafe { Pin::new_unchecked(&mut fut) };
+
+    loop {
+        match fut.as_mut().poll(&mut cx) {
+            Poll::Ready(output) => return output,
+            Poll::Pending => thread::park(),
+        }
+    }
+}
+
+// The runtime of `Pool::get_blocking` for the timers and the tasks spawned outside of an async context.
+// It's shared by all pools and started on the first use. It's leaked on purpose so it's never dropped on one of its
+// own worker threads.
+pub(crate) fn runtime() -> &'static tokio::runtime::Handle {
+    static START: Once = Once::new();
+    static mut RUNTIME: Option<&'static tokio::runtime::Runtime> = None;
+
+    // Safety: RUNTIME is only written once inside call_once and call_once synchronizes the reads after it.
+    unsafe {
+        START.call_once(|| {
+            let rt = tokio::runtime::Runtime::new().unwrap_or_else(|e| {
+                panic!("failed to start the runtime of Pool::get_blocking: {}", e)
+            });
+            RUNTIME = Some(Box::leak(Box::new(rt)));
+        });
+        RUNTIME.expect("runtime is started above").handle()
+    }
+}
+
+fn thread_waker(thread: Thread) -> Waker {
+    let ptr = Arc::into_raw(Arc::new(thread)) as *const ();
+    unsafe { Waker::from_raw(RawWaker::new(ptr, &VTABLE)) }
+}
+
+static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+
+unsafe fn clone(ptr: *const ()) -> RawWaker {
+    let thread = Arc::from_raw(ptr as *const Thread);
+    std::mem::forget(thread.clone());
+    RawWaker::new(Arc::into_raw(thread) as *const (), &VTABLE)
+}
+
+unsafe fn wake(ptr: *const ()) {
+    Arc::from_raw(ptr as *const Thread).unpark();
+}
+
+unsafe fn wake_by_ref(ptr: *const ()) {
+    (*(ptr as *const Thread)).unpark();
+}
+
+unsafe fn drop(ptr: *const ()) {
+    std::mem::drop(Arc::from_raw(ptr as *const Thread));
+}
@@ -1,5 +1,6 @@
 use std::num::NonZeroUsize;
 use std::task::Waker;
+use std::time::Instant;
 
 // This linked list come from https://github.com/async-rs/async-std/pull/370 by nbdd0121
 
@@ -9,12 +10,21 @@ struct WakerNode {
     /// Next `WakerNode` in the queue. If this node is the last node, it shall be null.
     next_in_queue: *mut WakerNode,
     waker: Option<Waker>,
+    /// The waiter is timing out after this deadline and should not be waken for a connection.
+    deadline: Option<Instant>,
 }
 
 pub(crate) struct WakerList {
     head: *mut WakerNode,
+    len: usize,
 }
 
+// Safety:
+// The raw pointers are only there because of the intrusive linked list. Every `WakerNode` is allocated by `insert`
+// and freed by `remove` so the nodes are exclusively owned by the `WakerList` and not shared with anyone else.
+// All the methods accessing the nodes take `&mut self` and the only content of a node is a `Waker`, which is
+// `Send` and `Sync`. So moving the list or sharing `&WakerList` across threads is as safe as doing so for a
+// `Vec<Option<Waker>>`.
 unsafe impl Send for WakerList {}
 
 unsafe impl Sync for WakerList {}
@@ -24,13 +34,20 @@ impl WakerList {
     pub(crate) fn new() -> Self {
         Self {
             head: std::ptr::null_mut(),
+            len: 0,
         }
     }
 
-    /// Insert a waker to the back of the list, and return its key.
-    pub(crate) fn insert(&mut self, waker: Option<Waker>) -> NonZeroUsize {
+    /// Insert a waker with an optional deadline to the back of the list, and return its key along with a position hint.
+    /// (The count of wakers ahead of it at the time of insertion)
+    pub(crate) fn insert(
+        &mut self,
+        waker: Option<Waker>,
+        deadline: Option<Instant>,
+    ) -> (NonZeroUsize, usize) {
         let node = Box::into_raw(Box::new(WakerNode {
             waker,
+            deadline,
             next_in_queue: std::ptr::null_mut(),
             prev_in_queue: std::ptr::null_mut(),
         }));
@@ -48,7 +65,13 @@ impl WakerList {
             }
         }
 
-        unsafe { NonZeroUsize::new_unchecked(node as usize) }
+        let position = self.len;
+        self.len += 1;
+
+        (
+            unsafe { NonZeroUsize::new_unchecked(node as usize) },
+            position,
+        )
     }
 
     /// Remove a waker by its key.
@@ -65,18 +88,20 @@ impl WakerList {
         if self.head == node {
             self.head = next;
         } else {
-            std::mem::replace(&mut (*prev).next_in_queue, next);
+            (*prev).next_in_queue = next;
         }
 
         // Special treatment on removing last node
         if next.is_null() {
             if !self.head.is_null() {
-                std::mem::replace(&mut (*self.head).prev_in_queue, prev);
+                (*self.head).prev_in_queue = prev;
             }
         } else {
-            std::mem::replace(&mut (*next).prev_in_queue, prev);
+            (*next).prev_in_queue = prev;
         }
 
+        self.len -= 1;
+
         Box::from_raw(node).waker
     }
 
@@ -89,10 +114,60 @@ impl WakerList {
         &mut (*(key.get() as *mut WakerNode)).waker
     }
 
-    //    /// Check if this list is empty.
-    //    pub(crate) fn is_empty(&self) -> bool {
-    //        self.head.is_null()
-    //    }
+    /// The count of wakers in this list.
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Check if this list is empty.
+    pub(crate) fn is_empty(&self) -> bool {
+        self.head.is_null()
+    }
+
+    /// Check if a key is still in the list.
+    #[cfg(all(debug_assertions, feature = "fairness-check"))]
+    pub(crate) fn contains(&self, key: NonZeroUsize) -> bool {
+        let mut ptr = self.head;
+        while !ptr.is_null() {
+            if ptr as usize == key.get() {
+                return true;
+            }
+            ptr = unsafe { (*ptr).next_in_queue };
+        }
+        false
+    }
+
+    /// Panic if the links of the list are broken or `len` doesn't match the count of nodes.
+    #[cfg(all(debug_assertions, feature = "fairness-check"))]
+    pub(crate) fn check_invariants(&self) {
+        if self.head.is_null() {
+            assert_eq!(self.len, 0, "WakerList: empty list with non zero len");
+            return;
+        }
+
+        let mut count = 0;
+        let mut prev = unsafe { (*self.head).prev_in_queue };
+        let mut ptr = self.head;
+        while !ptr.is_null() {
+            let node = unsafe { &*ptr };
+            if ptr != self.head {
+                assert_eq!(node.prev_in_queue, prev, "WakerList: broken prev link");
+            }
+            count += 1;
+            prev = ptr;
+            ptr = node.next_in_queue;
+        }
+
+        assert_eq!(
+            unsafe { (*self.head).prev_in_queue },
+            prev,
+            "WakerList: head doesn't point to the last node"
+        );
+        assert_eq!(
+            self.len, count,
+            "WakerList: len doesn't match the count of nodes"
+        );
+    }
 
     /// Get an iterator over all wakers.
     pub(crate) fn iter_mut(&mut self) -> Iter<'_> {
@@ -102,14 +177,32 @@ impl WakerList {
         }
     }
 
-    /// Wake the first waker in the list, and convert it to `None`. This function is named `weak` as
-    /// nothing is performed when the first waker is waken already.
+    /// Wake the first waker in the list that is not past its deadline, and convert it to `None`. This function is
+    /// named `weak` as nothing is performed when that waker is waken already.
     pub(crate) fn wake_one_weak(&mut self) -> Option<Waker> {
-        if let Some(opt_waker) = self.iter_mut().next() {
-            opt_waker.take()
-        } else {
-            None
+        let mut now = None;
+        let mut ptr = self.head;
+        while !ptr.is_null() {
+            let node = unsafe { &mut *ptr };
+            if let Some(deadline) = node.deadline {
+                let now = *now.get_or_insert_with(Instant::now);
+                if deadline <= now {
+                    ptr = node.next_in_queue;
+                    continue;
+                }
+            }
+            return node.waker.take();
         }
+        None
+    }
+
+    /// Take all the wakers in the list and convert them to `None`. The wakers are returned so they can be waken
+    /// after releasing the lock guarding the list.
+    ///
+    /// The nodes are kept in the list as their keys are still owned by the waiting futures. A future re-inserts its
+    /// waker or removes its node by key when it's polled or dropped.
+    pub(crate) fn drain_all(&mut self) -> Vec<Waker> {
+        self.iter_mut().filter_map(Option::take).collect()
     }
 }
 
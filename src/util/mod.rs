@@ -1 +1,5 @@
+pub(crate) mod atomic_duration;
+#[cfg(feature = "blocking")]
+pub(crate) mod block_on;
 pub(crate) mod linked_list;
+pub(crate) mod semaphore;
@@ -0,0 +1,79 @@
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::task::{Context, Poll, Waker};
+
+// a minimal async semaphore. All the waiters are woken when a permit is released so a waiter dropped after being
+// woken can't lose the permit for others. It's only used on the cold paths(e.g. establishing connections).
+pub(crate) struct Semaphore {
+    state: Mutex<State>,
+}
+
+struct State {
+    permits: usize,
+    waiters: Vec<Waker>,
+}
+
+impl Semaphore {
+    pub(crate) fn new(permits: usize) -> Self {
+        Semaphore {
+            state: Mutex::new(State {
+                permits,
+                waiters: Vec::new(),
+            }),
+        }
+    }
+
+    pub(crate) fn acquire(&self) -> Acquire<'_> {
+        Acquire { semaphore: self }
+    }
+
+    fn release(&self) {
+        let waiters = {
+            // the permit is released when unwinding from a panic so we don't panic again on a poisoned lock.
+            let mut state = self
+                .state
+                .lock()
+                .unwrap_or_else(|poisoned| poisoned.into_inner());
+            state.permits += 1;
+            std::mem::take(&mut state.waiters)
+        };
+        for waker in waiters.into_iter() {
+            waker.wake();
+        }
+    }
+}
+
+pub(crate) struct Acquire<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl<'a> Future for Acquire<'a> {
+    type Output = Permit<'a>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let mut state = self.semaphore.state.lock().unwrap();
+        if state.permits != 0 {
+            state.permits -= 1;
+            return Poll::Ready(Permit {
+                semaphore: self.semaphore,
+            });
+        }
+
+        if !state.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+            state.waiters.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+// the permit is released when dropped.
+pub(crate) struct Permit<'a> {
+    semaphore: &'a Semaphore,
+}
+
+impl Drop for Permit<'_> {
+    fn drop(&mut self) {
+        self.semaphore.release();
+    }
+}